@@ -0,0 +1,53 @@
+//! Worst-case latency of a single request: large primes go through all the
+//! Miller-Rabin rounds.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+fn is_prime(c: &mut Criterion) {
+    let mut group = c.benchmark_group("is_prime");
+
+    for n in [
+        1_000_000_007u64,
+        4_294_967_291,
+        3_825_123_056_546_413_051,
+        18_446_744_073_709_551_557,
+    ] {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter(|| protohakers::prime_time::is_prime(std::hint::black_box(n)))
+        });
+    }
+
+    group.finish();
+}
+
+fn request(c: &mut Criterion) {
+    let line = br#"{"method":"isPrime","number":18446744073709551557}"#;
+
+    c.bench_function("validate largest u64 prime", |b| {
+        b.iter(|| {
+            protohakers::prime_time::validate(std::hint::black_box(line), &Default::default())
+        })
+    });
+}
+
+fn is_prime_big(c: &mut Criterion) {
+    let mut group = c.benchmark_group("is_prime_big");
+    group.sample_size(10);
+
+    // worst case: the first prime with that many digits, every round passes
+    for digits in [100u32, 300, 1000] {
+        let mut n = num_bigint::BigUint::from(10u32).pow(digits - 1);
+        while !protohakers::prime_time::is_prime_big(&n) {
+            n += 1u32;
+        }
+
+        group.bench_with_input(BenchmarkId::from_parameter(digits), &n, |b, n| {
+            b.iter(|| protohakers::prime_time::is_prime_big(std::hint::black_box(n)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, is_prime, is_prime_big, request);
+criterion_main!(benches);
@@ -0,0 +1,251 @@
+//! Sockets opened by the service manager and inherited by the process, as
+//! done by systemd socket activation (see `sd_listen_fds(3)`).
+//!
+//! The sockets are matched to the services by name, set with
+//! `FileDescriptorName=` in the socket unit. When a single exercise is
+//! served, a lone socket is handed to it whatever its name, so that it does
+//! not need one.
+
+use crate::service::{Listener, Transport};
+
+/// First file descriptor passed by the service manager.
+const LISTEN_FDS_START: i32 = 3;
+
+/// Sockets inherited from the service manager, waiting to be taken by the
+/// services.
+#[derive(Default)]
+pub struct Inherited {
+    sockets: std::sync::Mutex<Vec<(String, socket2::Socket)>>,
+    /// The only socket can be taken by the only service.
+    single: bool,
+}
+
+impl Inherited {
+    /// `single_service` tells whether a single exercise is served.
+    fn new(sockets: Vec<(String, socket2::Socket)>, single_service: bool) -> Self {
+        Self {
+            single: single_service && sockets.len() == 1,
+            sockets: std::sync::Mutex::new(sockets),
+        }
+    }
+
+    /// Takes the sockets passed with `LISTEN_FDS`, if they are meant for
+    /// this process, and clears the variables so that they do not leak to
+    /// child processes. `single_service` tells whether a single exercise is
+    /// served, that takes a lone socket whatever its name.
+    ///
+    /// Clearing the variables races with the threads reading the
+    /// environment, it must be called before any is started, e.g. the ones
+    /// of the runtime.
+    pub fn from_env(single_service: bool) -> anyhow::Result<Self> {
+        let var = |name| std::env::var(name).ok();
+        let fds = parse(
+            var("LISTEN_PID").as_deref(),
+            var("LISTEN_FDS").as_deref(),
+            var("LISTEN_FDNAMES").as_deref(),
+            std::process::id(),
+        )?;
+
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(name);
+        }
+
+        Ok(Self::new(sockets(fds), single_service))
+    }
+
+    /// Takes the socket of the service `name` as a listener of the given
+    /// transport, `None` if there is none and the service has to bind one.
+    pub fn take(&self, name: &str, transport: Transport) -> anyhow::Result<Option<Listener>> {
+        let mut sockets = self.sockets.lock().unwrap();
+
+        let position = match sockets.iter().position(|(n, _)| n == name) {
+            Some(position) => Some(position),
+            None if self.single && !sockets.is_empty() => Some(0),
+            None => None,
+        };
+
+        let Some(position) = position else {
+            return Ok(None);
+        };
+
+        let (fd_name, socket) = sockets.remove(position);
+        let expected = match transport {
+            Transport::Tcp => socket2::Type::STREAM,
+            Transport::Udp => socket2::Type::DGRAM,
+        };
+
+        if socket.r#type()? != expected {
+            anyhow::bail!(
+                "inherited socket {} is not a {:?} socket",
+                fd_name,
+                transport
+            );
+        }
+
+        Ok(Some(Listener::from_socket(transport, socket)?))
+    }
+}
+
+/// File descriptors and names of the sockets described by the variables,
+/// none if they are not set or meant for another process.
+fn parse(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> anyhow::Result<Vec<(i32, String)>> {
+    let Some(fds) = fds else {
+        return Ok(vec![]);
+    };
+
+    if let Some(pid) = pid {
+        let pid: u32 = pid
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid LISTEN_PID: {}", pid))?;
+        if pid != own_pid {
+            return Ok(vec![]);
+        }
+    }
+
+    let n: i32 = fds
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid LISTEN_FDS: {}", fds))?;
+
+    let names: Vec<_> = match names {
+        Some(names) => names.split(':').map(str::to_string).collect(),
+        None => vec![],
+    };
+
+    Ok((0..n)
+        .map(|i| {
+            let name = names
+                .get(i as usize)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+            (LISTEN_FDS_START + i, name)
+        })
+        .collect())
+}
+
+#[cfg(unix)]
+fn sockets(fds: Vec<(i32, String)>) -> Vec<(String, socket2::Socket)> {
+    use std::os::fd::FromRawFd;
+
+    fds.into_iter()
+        .map(|(fd, name)| {
+            // SAFETY: the service manager passes these descriptors to the
+            // process, nothing else in the process owns them
+            let fd = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+            (name, socket2::Socket::from(fd))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn sockets(fds: Vec<(i32, String)>) -> Vec<(String, socket2::Socket)> {
+    if !fds.is_empty() {
+        tracing::warn!("socket activation is only supported on unix");
+    }
+
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_variables() {
+        assert_eq!(parse(None, None, None, 42).unwrap(), vec![]);
+        assert_eq!(parse(Some("7"), Some("2"), None, 42).unwrap(), vec![]);
+
+        assert_eq!(
+            parse(Some("42"), Some("2"), Some("prime_time:unusual_db"), 42).unwrap(),
+            vec![(3, "prime_time".to_string()), (4, "unusual_db".to_string())]
+        );
+        assert_eq!(
+            parse(None, Some("1"), None, 42).unwrap(),
+            vec![(3, "unknown".to_string())]
+        );
+
+        assert!(parse(Some("42"), Some("two"), None, 42).is_err());
+        assert!(parse(Some("me"), Some("1"), None, 42).is_err());
+    }
+
+    fn tcp_socket() -> socket2::Socket {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .expect("open a listener")
+            .into()
+    }
+
+    fn udp_socket() -> socket2::Socket {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .expect("bind udp socket")
+            .into()
+    }
+
+    #[tokio::test]
+    async fn take_by_name() {
+        let inherited = Inherited::new(
+            vec![
+                ("smoke_test".to_string(), tcp_socket()),
+                ("unusual_db".to_string(), udp_socket()),
+            ],
+            false,
+        );
+
+        assert!(inherited
+            .take("prime_time", Transport::Tcp)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            inherited.take("unusual_db", Transport::Udp).unwrap(),
+            Some(Listener::Udp(_))
+        ));
+        assert!(matches!(
+            inherited.take("smoke_test", Transport::Tcp).unwrap(),
+            Some(Listener::Tcp(_))
+        ));
+
+        // every socket is taken once
+        assert!(inherited
+            .take("smoke_test", Transport::Tcp)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn single_socket() {
+        let socket = tcp_socket();
+        let address = socket.local_addr().unwrap().as_socket().unwrap();
+        let inherited = Inherited::new(vec![("protohackers.socket".to_string(), socket)], true);
+
+        let listener = inherited
+            .take("smoke_test", Transport::Tcp)
+            .unwrap()
+            .expect("the only socket is taken");
+        assert_eq!(listener.local_addr().unwrap(), address.into());
+    }
+
+    #[tokio::test]
+    async fn single_socket_of_several_services() {
+        let inherited = Inherited::new(vec![("prime_time".to_string(), tcp_socket())], false);
+
+        // the socket goes to its service, whoever asks first
+        assert!(inherited
+            .take("smoke_test", Transport::Tcp)
+            .unwrap()
+            .is_none());
+        assert!(inherited
+            .take("prime_time", Transport::Tcp)
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn transport_mismatch() {
+        let inherited = Inherited::new(vec![("unusual_db".to_string(), tcp_socket())], false);
+
+        assert!(inherited.take("unusual_db", Transport::Udp).is_err());
+    }
+}
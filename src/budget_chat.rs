@@ -17,43 +17,142 @@ struct Client {
     rx: tokio::sync::broadcast::Receiver<String>,
 }
 
-pub async fn run(listener: tokio::net::TcpListener) -> anyhow::Result<()> {
-    let (tx, rx) = tokio::sync::broadcast::channel(100);
-    let participants = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+/// Settings of the chat room.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Line sent to the clients as soon as they connect.
+    pub welcome: String,
+
+    /// Max length of the names.
+    pub max_name_length: usize,
+
+    /// Messages that can wait for a slow client before it misses some.
+    pub broadcast_capacity: usize,
+}
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let c = Client {
-                    name: String::new(),
-                    status: Status::Identification,
-                    tx: tx.clone(),
-                    rx: rx.resubscribe(),
-                };
-
-                let p = participants.clone();
-
-                tokio::spawn(async move {
-                    match handler(stream, c, p).await {
-                        Ok(_) => (),
-                        Err(e) => tracing::error!("error on handling connection: {}", e),
-                    }
-                });
-            }
-            Err(e) => return Err(e.into()),
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            welcome: "Welcome to budgetchat! What shall I call you?".to_string(),
+            max_name_length: 32,
+            broadcast_capacity: 100,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.welcome.is_empty() || self.welcome.contains('\n') {
+            anyhow::bail!("welcome must be a single non empty line");
+        }
+
+        if self.max_name_length == 0 {
+            anyhow::bail!("max_name_length must be at least 1");
+        }
+
+        if self.broadcast_capacity == 0 {
+            anyhow::bail!("broadcast_capacity must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+/// Problem 3: a chat room broadcasting messages to every joined client.
+pub struct BudgetChat {
+    config: std::sync::Arc<Config>,
+    tx: tokio::sync::broadcast::Sender<String>,
+    participants: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl BudgetChat {
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    /// Creates the room, the config has to be valid.
+    pub fn with_config(config: Config) -> Self {
+        let (tx, _) = tokio::sync::broadcast::channel(config.broadcast_capacity);
+
+        Self {
+            config: std::sync::Arc::new(config),
+            tx,
+            participants: std::sync::Arc::new(std::sync::Mutex::new(vec![])),
         }
     }
 }
 
+impl Default for BudgetChat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Metrics {
+    members: crate::metrics::Gauge,
+    messages: crate::metrics::Counter,
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: std::sync::OnceLock<Metrics> = std::sync::OnceLock::new();
+    METRICS.get_or_init(|| {
+        let r = crate::metrics::registry();
+        Metrics {
+            members: r.gauge("budget_chat_members", "Members in the room.", "budget_chat"),
+            messages: r.counter(
+                "budget_chat_messages_total",
+                "Chat messages sent by the members.",
+                "budget_chat",
+            ),
+        }
+    })
+}
+
+impl crate::service::Service for BudgetChat {
+    fn name(&self) -> &'static str {
+        "budget_chat"
+    }
+
+    fn refusal(&self, reason: crate::limits::Refusal) -> Option<Vec<u8>> {
+        Some(format!("* Connection refused: {reason}\n").into_bytes())
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        let c = Client {
+            name: String::new(),
+            status: Status::Identification,
+            tx: self.tx.clone(),
+            rx: self.tx.subscribe(),
+        };
+
+        Box::pin(handler(
+            stream,
+            c,
+            self.config.clone(),
+            self.participants.clone(),
+            shutdown,
+        ))
+    }
+}
+
 async fn handler(
-    mut stream: tokio::net::TcpStream,
+    stream: crate::service::Stream,
     mut client: Client,
+    config: std::sync::Arc<Config>,
     participants: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    shutdown: crate::shutdown::Shutdown,
 ) -> anyhow::Result<()> {
+    let peer = stream.peer().clone();
+
     // only first time the client will receive the welcome message
-    let (mut r, mut w) = stream.split();
-    let welcome_msg = b"Welcome to budgetchat! What shall I call you?\n";
-    w.write_all(welcome_msg).await?;
+    let (mut r, mut w) = tokio::io::split(stream);
+    w.write_all(format!("{}\n", config.welcome).as_bytes())
+        .await?;
 
     let mut bf = tokio::io::BufReader::new(&mut r);
     let mut buffer = Vec::new();
@@ -61,21 +160,30 @@ async fn handler(
     loop {
         tokio::select! {
             n_bytes = bf.read_until(b'\n', &mut buffer) => {
-                if n_bytes? == 0 {
-                    break;
+                match n_bytes {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(e) => match crate::timeouts::Expired::from_io(&e) {
+                        Some(expired) => {
+                            w.write_all(format!("* You have been disconnected: {expired}\n").as_bytes()).await?;
+                            break;
+                        }
+                        None => return Err(e.into()),
+                    },
                 }
 
                 match client.status {
                         Status::Identification => {
                             buffer.pop(); // remove the newline
 
-                            if !name_is_valid(&buffer) {
+                            if !name_is_valid(&buffer, config.max_name_length) {
                                 tracing::info!("name is invalid: {:?}", std::str::from_utf8(&buffer));
                                 w.write_all(b"error: name is empty\n").await?;
                                 break;
                             }
 
                             let name = String::from_utf8(buffer.clone()).unwrap();
+                            tracing::info!("{} joined from {}", name, peer);
                             client.status = Status::Joined;
                             client.name = name.to_string();
                             client.tx.send(format!("* {name} has entered the room")).unwrap();
@@ -83,15 +191,25 @@ async fn handler(
                             let users_already_in = participants.lock().unwrap().join(", ");
                             w.write_all(format!("* The room contains: {}\n", users_already_in).as_bytes()).await?;
 
-                            participants.lock().unwrap().push(name.to_string());
+                            let mut participants = participants.lock().unwrap();
+                            participants.push(name.to_string());
+                            metrics().members.set(participants.len() as i64);
                         }
 
-                        Status::Joined => _ = client.tx.send(format!("[{}] {}", client.name, String::from_utf8(buffer.clone()).unwrap())).unwrap(),
+                        Status::Joined => {
+                            metrics().messages.inc();
+                            _ = client.tx.send(format!("[{}] {}", client.name, String::from_utf8(buffer.clone()).unwrap())).unwrap()
+                        }
                 }
 
                 buffer.clear();
             }
 
+            _ = shutdown.wait() => {
+                w.write_all(b"* The server is shutting down\n").await?;
+                break;
+            }
+
             msg = client.rx.recv() => {
                 if client.status != Status::Joined {
                     continue;
@@ -121,14 +239,17 @@ async fn handler(
             .send(format!("* {} has left the room", client.name))
             .unwrap();
 
-        participants.lock().unwrap().retain(|n| n != &client.name);
+        let mut participants = participants.lock().unwrap();
+        participants.retain(|n| n != &client.name);
+        metrics().members.set(participants.len() as i64);
     }
 
     Ok(())
 }
 
-fn name_is_valid(name: &[u8]) -> bool {
-    if name.is_empty() || name.len() > 32 {
+/// Names are made of 1 to `max_length` alphanumeric characters.
+pub fn name_is_valid(name: &[u8], max_length: usize) -> bool {
+    if name.is_empty() || name.len() > max_length {
         return false;
     }
 
@@ -138,164 +259,168 @@ fn name_is_valid(name: &[u8]) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::io::AsyncWriteExt;
+    use crate::test_support::TestServer;
+
+    const WELCOME: &str = "Welcome to budgetchat! What shall I call you?";
 
     #[tokio::test]
     async fn join_user_ok() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
-
-        let local_addr = listener.local_addr().expect("local address works");
-
-        tokio::spawn(async move {
-            run(listener).await.expect("run works");
-        });
-
-        // alice client
-        let mut stream_alice = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
-
-        let (mut r_alice, mut w_alice) = stream_alice.split();
-        w_alice.write_all(b"alice\n").await.expect("to write name");
-        w_alice.flush().await.expect("to flush msg");
-
-        // federico client
-        let mut stream_federico = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
-
-        let (mut r_federico, mut w_federico) = stream_federico.split();
-        let bf = tokio::io::BufReader::new(&mut r_federico);
-        let mut s = bf.split(b'\n');
-        let welcome_msg = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
+        let server = TestServer::start(BudgetChat::new()).await;
 
-        assert_eq!(
-            welcome_msg,
-            b"Welcome to budgetchat! What shall I call you?"
-        );
+        let mut alice = server.line_client().await;
+        alice.send("alice").await;
+        assert_eq!(alice.recv().await.unwrap(), WELCOME);
+        assert_eq!(alice.recv().await.unwrap(), "* The room contains: ");
 
-        w_federico
-            .write_all(b"federico\n")
-            .await
-            .expect("to write name");
-        w_federico.flush().await.expect("to flush msg");
-
-        w_federico
-            .write_all(b"Just one more thing\n")
-            .await
-            .expect("to write name");
-
-        let list_users = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
-
-        assert_eq!(list_users, b"* The room contains: alice");
-
-        let bf = tokio::io::BufReader::new(&mut r_alice);
-        let mut s = bf.split(b'\n');
-        let welcome_msg = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
+        let mut federico = server.line_client().await;
+        assert_eq!(federico.recv().await.unwrap(), WELCOME);
+        federico.send("federico").await;
+        federico.send("Just one more thing").await;
+        assert_eq!(federico.recv().await.unwrap(), "* The room contains: alice");
 
         assert_eq!(
-            welcome_msg,
-            b"Welcome to budgetchat! What shall I call you?"
+            alice.recv().await.unwrap(),
+            "* federico has entered the room"
+        );
+        assert_eq!(
+            alice.recv().await.unwrap(),
+            "[federico] Just one more thing"
         );
 
-        let room_empty = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
-
-        assert_eq!(room_empty, b"* The room contains: ");
-
-        let new_entry = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
-
-        assert_eq!(new_entry, b"* federico has entered the room");
-
-        let msg_from_federico = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
-
-        assert_eq!(msg_from_federico, b"[federico] Just one more thing");
-
-        w_federico.shutdown().await.expect("to shutdown");
+        federico.shutdown().await;
+        assert_eq!(alice.recv().await.unwrap(), "* federico has left the room");
+    }
 
-        let leave_msg = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
+    #[test]
+    fn check_name() {
+        assert!(!name_is_valid(b"", 32));
+        assert!(!name_is_valid(b" ", 32));
+        assert!(name_is_valid(b"John", 32));
+        assert!(!name_is_valid(b"John Doe 123", 32));
+        assert!(!name_is_valid(b"123456789012345678901234567890123", 32));
+        assert!(name_is_valid(b"1234567890123456", 32));
+        assert!(name_is_valid(b"LargeNewbie639", 32));
+    }
 
-        assert_eq!(leave_msg, b"* federico has left the room");
+    #[tokio::test]
+    async fn custom_config() {
+        let server = TestServer::start(BudgetChat::with_config(Config {
+            welcome: "Who are you?".to_string(),
+            max_name_length: 4,
+            ..Config::default()
+        }))
+        .await;
+
+        let mut client = server.line_client().await;
+        assert_eq!(client.recv().await.unwrap(), "Who are you?");
+        client.send("alice").await;
+        assert_eq!(client.recv().await.unwrap(), "error: name is empty");
+
+        let mut client = server.line_client().await;
+        assert_eq!(client.recv().await.unwrap(), "Who are you?");
+        client.send("bob").await;
+        assert_eq!(client.recv().await.unwrap(), "* The room contains: ");
     }
 
     #[test]
-    fn check_name() {
-        assert!(!name_is_valid(b""));
-        assert!(!name_is_valid(b" "));
-        assert!(name_is_valid(b"John"));
-        assert!(!name_is_valid(b"John Doe 123"));
-        assert!(!name_is_valid(b"123456789012345678901234567890123"));
-        assert!(name_is_valid(b"1234567890123456"));
-        assert!(name_is_valid(b"LargeNewbie639"));
+    fn validate_config() {
+        assert!(Config::default().validate().is_ok());
+
+        let invalid = [
+            Config {
+                welcome: String::new(),
+                ..Config::default()
+            },
+            Config {
+                welcome: "Hi\nthere".to_string(),
+                ..Config::default()
+            },
+            Config {
+                max_name_length: 0,
+                ..Config::default()
+            },
+            Config {
+                broadcast_capacity: 0,
+                ..Config::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
     }
 
     #[tokio::test]
     async fn illegal_name_for_join() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
+        let server = TestServer::start(BudgetChat::new()).await;
+        let mut client = server.line_client().await;
 
-        let local_addr = listener.local_addr().expect("local address works");
+        assert_eq!(client.recv().await.unwrap(), WELCOME);
 
-        tokio::spawn(async move {
-            run(listener).await.expect("run works");
-        });
+        client.send("").await;
+        client.shutdown().await;
 
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
+        assert_eq!(client.recv().await.unwrap(), "error: name is empty");
+    }
+
+    #[tokio::test]
+    async fn disconnect_silent_client() {
+        let options = crate::service::Options {
+            timeouts: crate::timeouts::Timeouts {
+                read: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = TestServer::start_with(std::sync::Arc::new(BudgetChat::new()), options).await;
+        let mut client = server.line_client().await;
+
+        assert_eq!(client.recv().await.unwrap(), WELCOME);
+        assert_eq!(
+            client.recv().await.unwrap(),
+            "* You have been disconnected: nothing received for too long"
+        );
+        assert!(client.recv().await.is_none());
+    }
 
-        let (mut r, mut w) = stream.split();
-        let mut bf = tokio::io::BufReader::new(&mut r);
-        let mut v = vec![];
-        bf.read_until(b'\n', &mut v)
-            .await
-            .expect("to read welcome msg");
+    #[tokio::test]
+    async fn notify_shutdown() {
+        let server = TestServer::start(BudgetChat::new()).await;
+        let mut client = server.line_client().await;
 
-        assert_eq!(v, b"Welcome to budgetchat! What shall I call you?\n");
-        v.clear();
+        client.send("alice").await;
+        assert_eq!(client.recv().await.unwrap(), WELCOME);
+        assert_eq!(client.recv().await.unwrap(), "* The room contains: ");
 
-        w.write(b"\n").await.expect("to write name");
-        w.flush().await.expect("to flush msg");
-        w.shutdown().await.expect("shutdown");
+        server.trigger_shutdown();
 
-        let mut s = bf.split(b'\n');
+        assert_eq!(
+            client.recv().await.unwrap(),
+            "* The server is shutting down"
+        );
+        assert!(client.recv().await.is_none());
 
-        let msg_error = s
-            .next_segment()
-            .await
-            .expect("to read segment")
-            .expect("to read segment");
+        server.stop().await.expect("server stops cleanly");
+    }
 
-        assert_eq!(msg_error, b"error: name is empty");
+    #[tokio::test]
+    async fn refuse_over_the_limits() {
+        let options = crate::service::Options {
+            admission: std::sync::Arc::new(crate::limits::Admission::new(crate::limits::Limits {
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let server = TestServer::start_with(std::sync::Arc::new(BudgetChat::new()), options).await;
+
+        let mut alice = server.line_client().await;
+        assert_eq!(alice.recv().await.unwrap(), WELCOME);
+
+        let mut bob = server.line_client().await;
+        assert_eq!(
+            bob.recv().await.unwrap(),
+            "* Connection refused: too many connections from your address"
+        );
+        assert_eq!(bob.recv().await, None);
     }
 }
@@ -0,0 +1,632 @@
+use protohakers::{mob_in_the_middle, pest_control, prime_time};
+
+#[derive(clap::Parser, Debug)]
+#[command(version, about = "Solutions for protohackers.com exercises")]
+pub struct Cli {
+    /// Address the server binds to.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_BIND",
+        default_value = "0.0.0.0",
+        global = true
+    )]
+    pub bind: std::net::IpAddr,
+
+    /// Port the server listens on.
+    #[arg(long, env = "PROTOHACKERS_PORT", default_value_t = 8000, global = true)]
+    pub port: u16,
+
+    /// Address to listen on instead of `--bind` and `--port`: `IP:PORT`,
+    /// `[IPV6]:PORT` (`[::]` accepts IPv4 clients too) or the path of a unix
+    /// socket. Can be repeated.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_LISTEN",
+        value_name = "ADDRESS",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub listen: Vec<protohakers::service::Address>,
+
+    /// Permissions of the unix sockets, in octal, e.g. `660`.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_UNIX_SOCKET_MODE",
+        value_name = "MODE",
+        value_parser = parse_mode,
+        global = true
+    )]
+    pub unix_socket_mode: Option<u32>,
+
+    /// Seconds to wait for in-flight connections when shutting down.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_DRAIN_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = 5,
+        global = true
+    )]
+    pub drain_timeout: u64,
+
+    /// Format of the logs, levels are set with `RUST_LOG` (default `info`).
+    #[arg(
+        long,
+        env = "PROTOHACKERS_LOG_FORMAT",
+        value_enum,
+        default_value_t = LogFormat::Text,
+        global = true
+    )]
+    pub log_format: LogFormat,
+
+    /// Address of the HTTP endpoint exposing the Prometheus metrics on
+    /// `/metrics`, disabled if not set.
+    #[arg(long, env = "PROTOHACKERS_METRICS_ADDRESS", global = true)]
+    pub metrics_address: Option<std::net::SocketAddr>,
+
+    /// Seconds without traffic before a connection is closed.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_IDLE_TIMEOUT",
+        value_name = "SECONDS",
+        global = true
+    )]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds a connection waits for the client to send something.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_READ_TIMEOUT",
+        value_name = "SECONDS",
+        global = true
+    )]
+    pub read_timeout: Option<u64>,
+
+    /// Max seconds a connection can last.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_SESSION_TIMEOUT",
+        value_name = "SECONDS",
+        global = true
+    )]
+    pub session_timeout: Option<u64>,
+
+    /// Max connections served at the same time by all the services.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_MAX_CONNECTIONS",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub max_connections: Option<usize>,
+
+    /// Max connections served at the same time for a single client address.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_MAX_CONNECTIONS_PER_IP",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Whether connections start with a PROXY protocol header (v1 or v2)
+    /// carrying the address of the client: off, optional or required.
+    /// With optional, the clients can send a header too: it must be used
+    /// with --trusted-proxy, or not exposed to untrusted clients.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_PROXY_PROTOCOL",
+        value_name = "MODE",
+        default_value = "off",
+        global = true
+    )]
+    pub proxy_protocol: protohakers::proxy_protocol::Mode,
+
+    /// Address of a proxy allowed to send PROXY protocol headers, any peer
+    /// when none is given. Can be repeated.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_TRUSTED_PROXIES",
+        value_name = "IP",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub trusted_proxy: Vec<std::net::IpAddr>,
+
+    /// Max new connections accepted per second.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_ACCEPT_RATE",
+        value_name = "PER_SECOND",
+        value_parser = clap::value_parser!(u32).range(1..),
+        global = true
+    )]
+    pub accept_rate: Option<u32>,
+
+    #[command(flatten)]
+    pub services: ServiceArgs,
+
+    #[command(subcommand)]
+    pub exercise: Exercise,
+}
+
+impl Cli {
+    /// Addresses of a single exercise.
+    pub fn addresses(&self) -> Vec<protohakers::service::Address> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+
+        vec![self.resolve(&Listen::Port(self.port))]
+    }
+
+    /// Address of a `--serve` of `multi`, ports are on the bind address.
+    pub fn resolve(&self, listen: &Listen) -> protohakers::service::Address {
+        match listen {
+            Listen::Port(port) => std::net::SocketAddr::new(self.bind, *port).into(),
+            Listen::Address(address) => address.clone(),
+        }
+    }
+
+    pub fn timeouts(&self) -> protohakers::timeouts::Timeouts {
+        protohakers::timeouts::Timeouts {
+            idle: self.idle_timeout.map(std::time::Duration::from_secs),
+            read: self.read_timeout.map(std::time::Duration::from_secs),
+            session: self.session_timeout.map(std::time::Duration::from_secs),
+        }
+    }
+
+    pub fn limits(&self) -> protohakers::limits::Limits {
+        protohakers::limits::Limits {
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            accept_rate: self.accept_rate,
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Exercise {
+    /// 0. Smoke Test
+    SmokeTest,
+
+    /// 1. Prime Time
+    PrimeTime,
+
+    /// 2. Means to an End
+    MeansToAnEnd,
+
+    /// 3. Budget Chat
+    BudgetChat,
+
+    /// 4. Unusual Database Program
+    UnusualDb,
+
+    /// 5. Mob in the Middle
+    MobInTheMiddle,
+
+    /// 6. Speed Daemon
+    SpeedDaemon,
+
+    /// 7. Line Reversal
+    LineReversal,
+
+    /// 8. Insecure Sockets Layer
+    InsecureSocketsLayer,
+
+    /// 9. Job Centre
+    JobCentre,
+
+    /// 10. Voracious Code Storage
+    VoraciousCodeStorage,
+
+    /// 11. Pest Control
+    PestControl,
+
+    /// Run several exercises at once, each one on its own port.
+    Multi {
+        /// Exercise to run and its port or address, e.g. `--serve
+        /// prime-time=8001` or `--serve prime-time=/run/prime-time.sock`.
+        /// Can be repeated, also for the same exercise to listen on several
+        /// addresses.
+        #[arg(long = "serve", value_name = "EXERCISE=PORT|ADDRESS", required = true, value_parser = parse_serve)]
+        serve: Vec<(Kind, Listen)>,
+    },
+
+    /// Run the exercises declared in a TOML config file, with their
+    /// addresses and settings.
+    Config {
+        /// Path of the config file.
+        #[arg(value_name = "FILE")]
+        path: std::path::PathBuf,
+
+        /// Only validate the config file.
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+/// Settings of the services that can be set from the command line: the
+/// upstream servers of the proxies and the size of the prime time numbers.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ServiceArgs {
+    /// Max digits of the numbers tested by prime time.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_MAX_DIGITS",
+        default_value_t = prime_time::Config::default().max_digits,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        global = true
+    )]
+    pub max_digits: usize,
+
+    /// Address of the upstream budget chat server.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_CHAT_ADDRESS",
+        default_value_t = mob_in_the_middle::Config::default().chat_address,
+        global = true
+    )]
+    pub chat_address: String,
+
+    /// Boguscoin address that replaces the ones found in the messages.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_BOGUSCOIN",
+        default_value_t = mob_in_the_middle::Config::default().boguscoin,
+        global = true
+    )]
+    pub boguscoin: String,
+
+    /// Address of the pest control authority server.
+    #[arg(
+        long,
+        env = "PROTOHACKERS_AUTHORITY_ADDRESS",
+        default_value_t = pest_control::Config::default().authority_address,
+        global = true
+    )]
+    pub authority_address: String,
+}
+
+impl ServiceArgs {
+    /// Settings of the services, the defaults for the ones without options,
+    /// validated as the ones of a config file.
+    pub fn parameters(&self) -> anyhow::Result<crate::config::Parameters> {
+        let parameters = crate::config::Parameters {
+            prime_time: prime_time::Config {
+                max_digits: self.max_digits,
+                ..Default::default()
+            },
+            mob_in_the_middle: mob_in_the_middle::Config {
+                chat_address: self.chat_address.clone(),
+                boguscoin: self.boguscoin.clone(),
+            },
+            pest_control: pest_control::Config {
+                authority_address: self.authority_address.clone(),
+            },
+            ..Default::default()
+        };
+        parameters.validate()?;
+
+        Ok(parameters)
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Where an exercise of `multi` listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listen {
+    /// Port on the bind address.
+    Port(u16),
+    Address(protohakers::service::Address),
+}
+
+/// Every exercise that can be served.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    SmokeTest,
+    PrimeTime,
+    MeansToAnEnd,
+    BudgetChat,
+    UnusualDb,
+    MobInTheMiddle,
+    SpeedDaemon,
+    LineReversal,
+    InsecureSocketsLayer,
+    JobCentre,
+    VoraciousCodeStorage,
+    PestControl,
+}
+
+fn parse_serve(s: &str) -> Result<(Kind, Listen), String> {
+    use clap::ValueEnum;
+
+    let (name, listen) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXERCISE=PORT, found `{s}`"))?;
+
+    let kind = Kind::from_str(name, true)?;
+    let listen = match listen.parse() {
+        Ok(port) => Listen::Port(port),
+        Err(_) => Listen::Address(listen.parse()?),
+    };
+
+    Ok((kind, listen))
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("invalid mode `{s}`: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn verify() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_exercise_and_address() {
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "prime-time",
+            "--bind",
+            "127.0.0.1",
+            "--port",
+            "9000",
+        ])
+        .expect("valid arguments");
+
+        assert!(matches!(cli.exercise, Exercise::PrimeTime));
+        assert_eq!(cli.addresses(), vec!["127.0.0.1:9000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_listen() {
+        use protohakers::service::Address;
+
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "prime-time",
+            "--listen",
+            "[::]:9000",
+            "--listen",
+            "/run/prime-time.sock",
+            "--unix-socket-mode",
+            "660",
+        ])
+        .expect("valid arguments");
+
+        assert_eq!(
+            cli.addresses(),
+            vec![
+                Address::Inet("[::]:9000".parse().unwrap()),
+                Address::Unix("/run/prime-time.sock".into()),
+            ]
+        );
+        assert_eq!(cli.unix_socket_mode, Some(0o660));
+
+        assert!(Cli::try_parse_from(["protohakers", "smoke-test", "--listen", "nowhere"]).is_err());
+        assert!(
+            Cli::try_parse_from(["protohakers", "smoke-test", "--unix-socket-mode", "9"]).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_log_format() {
+        let cli = Cli::try_parse_from(["protohakers", "smoke-test"]).expect("valid arguments");
+        assert_eq!(cli.log_format, LogFormat::Text);
+
+        let cli = Cli::try_parse_from(["protohakers", "smoke-test", "--log-format", "json"])
+            .expect("valid arguments");
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert_eq!(cli.metrics_address, None);
+    }
+
+    #[test]
+    fn parse_metrics_address() {
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "smoke-test",
+            "--metrics-address",
+            "127.0.0.1:9100",
+        ])
+        .expect("valid arguments");
+
+        assert_eq!(cli.metrics_address, Some("127.0.0.1:9100".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_timeouts() {
+        let cli = Cli::try_parse_from(["protohakers", "smoke-test"]).expect("valid arguments");
+        assert_eq!(cli.timeouts(), protohakers::timeouts::Timeouts::default());
+
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "smoke-test",
+            "--idle-timeout",
+            "60",
+            "--session-timeout",
+            "3600",
+        ])
+        .expect("valid arguments");
+
+        let timeouts = cli.timeouts();
+        assert_eq!(timeouts.idle, Some(std::time::Duration::from_secs(60)));
+        assert_eq!(timeouts.read, None);
+        assert_eq!(timeouts.session, Some(std::time::Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn parse_limits() {
+        let cli = Cli::try_parse_from(["protohakers", "smoke-test"]).expect("valid arguments");
+        assert_eq!(cli.limits(), protohakers::limits::Limits::default());
+
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "smoke-test",
+            "--max-connections",
+            "1000",
+            "--accept-rate",
+            "50",
+        ])
+        .expect("valid arguments");
+
+        let limits = cli.limits();
+        assert_eq!(limits.max_connections, Some(1000));
+        assert_eq!(limits.max_connections_per_ip, None);
+        assert_eq!(limits.accept_rate, Some(50));
+
+        assert!(Cli::try_parse_from(["protohakers", "smoke-test", "--accept-rate", "-1"]).is_err());
+
+        // a limit of 0 would refuse every connection
+        for flag in [
+            "--max-connections",
+            "--max-connections-per-ip",
+            "--accept-rate",
+        ] {
+            assert!(Cli::try_parse_from(["protohakers", "smoke-test", flag, "0"]).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_proxy_protocol() {
+        use protohakers::proxy_protocol::Mode;
+
+        let cli = Cli::try_parse_from(["protohakers", "smoke-test"]).expect("valid arguments");
+        assert_eq!(cli.proxy_protocol, Mode::Off);
+
+        let cli =
+            Cli::try_parse_from(["protohakers", "smoke-test", "--proxy-protocol", "required"])
+                .expect("valid arguments");
+        assert_eq!(cli.proxy_protocol, Mode::Required);
+
+        assert!(
+            Cli::try_parse_from(["protohakers", "smoke-test", "--proxy-protocol", "v2"]).is_err()
+        );
+
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "smoke-test",
+            "--trusted-proxy",
+            "10.0.0.5",
+            "--trusted-proxy",
+            "2001:db8::5",
+        ])
+        .expect("valid arguments");
+        assert_eq!(
+            cli.trusted_proxy,
+            [
+                "10.0.0.5".parse::<std::net::IpAddr>().unwrap(),
+                "2001:db8::5".parse().unwrap()
+            ]
+        );
+        assert!(
+            Cli::try_parse_from(["protohakers", "smoke-test", "--trusted-proxy", "proxy"]).is_err()
+        );
+    }
+
+    #[test]
+    fn unknown_exercise() {
+        assert!(Cli::try_parse_from(["protohakers", "not-an-exercise"]).is_err());
+    }
+
+    #[test]
+    fn parse_multi() {
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "multi",
+            "--serve",
+            "smoke-test=8000",
+            "--serve",
+            "unusual-db=8004",
+            "--serve",
+            "smoke-test=unix:echo.sock",
+        ])
+        .expect("valid arguments");
+
+        match cli.exercise {
+            Exercise::Multi { serve } => assert_eq!(
+                serve,
+                vec![
+                    (Kind::SmokeTest, Listen::Port(8000)),
+                    (Kind::UnusualDb, Listen::Port(8004)),
+                    (
+                        Kind::SmokeTest,
+                        Listen::Address(protohakers::service::Address::Unix("echo.sock".into()))
+                    ),
+                ]
+            ),
+            e => panic!("unexpected exercise {:?}", e),
+        }
+
+        assert!(Cli::try_parse_from(["protohakers", "multi", "--serve", "smoke-test"]).is_err());
+        assert!(Cli::try_parse_from(["protohakers", "multi", "--serve", "nope=1"]).is_err());
+        assert!(Cli::try_parse_from(["protohakers", "multi"]).is_err());
+    }
+
+    #[test]
+    fn parse_config() {
+        let cli = Cli::try_parse_from(["protohakers", "config", "protohackers.toml", "--check"])
+            .expect("valid arguments");
+
+        match cli.exercise {
+            Exercise::Config { path, check } => {
+                assert_eq!(path, std::path::PathBuf::from("protohackers.toml"));
+                assert!(check);
+            }
+            e => panic!("unexpected exercise {:?}", e),
+        }
+
+        assert!(Cli::try_parse_from(["protohakers", "config"]).is_err());
+    }
+
+    #[test]
+    fn service_parameters() {
+        let cli =
+            Cli::try_parse_from(["protohakers", "mob-in-the-middle"]).expect("valid arguments");
+        assert_eq!(
+            cli.services.parameters().unwrap(),
+            crate::config::Parameters::default()
+        );
+
+        let cli = Cli::try_parse_from([
+            "protohakers",
+            "mob-in-the-middle",
+            "--chat-address",
+            "127.0.0.1:16963",
+        ])
+        .expect("valid arguments");
+        assert_eq!(
+            cli.services
+                .parameters()
+                .unwrap()
+                .mob_in_the_middle
+                .chat_address,
+            "127.0.0.1:16963"
+        );
+
+        // the options are validated as the settings of the config file
+        let cli =
+            Cli::try_parse_from(["protohakers", "mob-in-the-middle", "--boguscoin", "garbage"])
+                .expect("valid arguments");
+        assert_eq!(
+            cli.services.parameters().unwrap_err().to_string(),
+            "mob_in_the_middle: boguscoin `garbage` is not a boguscoin address"
+        );
+
+        let cli = Cli::try_parse_from(["protohakers", "pest-control", "--authority-address", ""])
+            .expect("valid arguments");
+        assert!(cli.services.parameters().is_err());
+    }
+}
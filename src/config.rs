@@ -0,0 +1,341 @@
+//! Config file declaring the services to run, where they listen and their
+//! settings, e.g.:
+//!
+//! ```toml
+//! [smoke_test]
+//! listen = ["0.0.0.0:8000"]
+//!
+//! [budget_chat]
+//! listen = ["[::]:8003", "/run/budget-chat.sock"]
+//!
+//! [budget_chat.settings]
+//! welcome = "Hello! What is your name?"
+//! max_name_length = 16
+//! ```
+
+use crate::cli::Kind;
+use protohakers::{
+    budget_chat, mob_in_the_middle, pest_control, prime_time, service::Address, unusual_db,
+    voracious_code_storage,
+};
+
+/// Services of the config file, missing ones are not served.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub smoke_test: Option<Section>,
+    pub prime_time: Option<Section<prime_time::Config>>,
+    pub means_to_an_end: Option<Section>,
+    pub budget_chat: Option<Section<budget_chat::Config>>,
+    pub unusual_db: Option<Section<unusual_db::Config>>,
+    pub mob_in_the_middle: Option<Section<mob_in_the_middle::Config>>,
+    pub speed_daemon: Option<Section>,
+    pub line_reversal: Option<Section>,
+    pub insecure_sockets_layer: Option<Section>,
+    pub job_centre: Option<Section>,
+    pub voracious_code_storage: Option<Section<voracious_code_storage::Config>>,
+    pub pest_control: Option<Section<pest_control::Config>>,
+}
+
+/// A service of the config file.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Section<S = NoSettings> {
+    /// Disabled services are kept in the file but not served.
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+
+    /// Addresses the service listens on.
+    #[serde(default)]
+    pub listen: Vec<Address>,
+
+    #[serde(default)]
+    pub settings: S,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Settings of the services without any.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoSettings {}
+
+/// Settings of the services, the defaults for the ones not configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Parameters {
+    pub prime_time: prime_time::Config,
+    pub budget_chat: budget_chat::Config,
+    pub unusual_db: unusual_db::Config,
+    pub mob_in_the_middle: mob_in_the_middle::Config,
+    pub voracious_code_storage: voracious_code_storage::Config,
+    pub pest_control: pest_control::Config,
+}
+
+impl Parameters {
+    /// Validates the settings of every service, whether they come from the
+    /// config file or the command line.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let settings = [
+            ("prime_time", self.prime_time.validate()),
+            ("budget_chat", self.budget_chat.validate()),
+            ("unusual_db", self.unusual_db.validate()),
+            ("mob_in_the_middle", self.mob_in_the_middle.validate()),
+            (
+                "voracious_code_storage",
+                self.voracious_code_storage.validate(),
+            ),
+            ("pest_control", self.pest_control.validate()),
+        ];
+        for (name, result) in settings {
+            if let Err(e) = result {
+                anyhow::bail!("{}: {}", name, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Name, kind, enabled flag and addresses of a configured service.
+type Entry<'a> = (&'static str, Kind, bool, &'a [Address]);
+
+fn entry<'a, S>(
+    name: &'static str,
+    kind: Kind,
+    section: &'a Option<Section<S>>,
+) -> Option<Entry<'a>> {
+    section
+        .as_ref()
+        .map(|s| (name, kind, s.enabled, s.listen.as_slice()))
+}
+
+impl Config {
+    /// Reads and validates the config file at `path`.
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("unable to read {}: {}", path.display(), e))?;
+
+        Self::parse(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn entries(&self) -> Vec<Entry<'_>> {
+        [
+            entry("smoke_test", Kind::SmokeTest, &self.smoke_test),
+            entry("prime_time", Kind::PrimeTime, &self.prime_time),
+            entry("means_to_an_end", Kind::MeansToAnEnd, &self.means_to_an_end),
+            entry("budget_chat", Kind::BudgetChat, &self.budget_chat),
+            entry("unusual_db", Kind::UnusualDb, &self.unusual_db),
+            entry(
+                "mob_in_the_middle",
+                Kind::MobInTheMiddle,
+                &self.mob_in_the_middle,
+            ),
+            entry("speed_daemon", Kind::SpeedDaemon, &self.speed_daemon),
+            entry("line_reversal", Kind::LineReversal, &self.line_reversal),
+            entry(
+                "insecure_sockets_layer",
+                Kind::InsecureSocketsLayer,
+                &self.insecure_sockets_layer,
+            ),
+            entry("job_centre", Kind::JobCentre, &self.job_centre),
+            entry(
+                "voracious_code_storage",
+                Kind::VoraciousCodeStorage,
+                &self.voracious_code_storage,
+            ),
+            entry("pest_control", Kind::PestControl, &self.pest_control),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let enabled: Vec<_> = self
+            .entries()
+            .into_iter()
+            .filter(|(_, _, enabled, _)| *enabled)
+            .collect();
+
+        if enabled.is_empty() {
+            anyhow::bail!("no service enabled");
+        }
+
+        for (name, _, _, listen) in &enabled {
+            if listen.is_empty() {
+                anyhow::bail!("{}.listen: no address to listen on", name);
+            }
+        }
+
+        let settings = [
+            (
+                "prime_time",
+                self.prime_time.as_ref().map(|s| s.settings.validate()),
+            ),
+            (
+                "budget_chat",
+                self.budget_chat.as_ref().map(|s| s.settings.validate()),
+            ),
+            (
+                "unusual_db",
+                self.unusual_db.as_ref().map(|s| s.settings.validate()),
+            ),
+            (
+                "mob_in_the_middle",
+                self.mob_in_the_middle
+                    .as_ref()
+                    .map(|s| s.settings.validate()),
+            ),
+            (
+                "voracious_code_storage",
+                self.voracious_code_storage
+                    .as_ref()
+                    .map(|s| s.settings.validate()),
+            ),
+            (
+                "pest_control",
+                self.pest_control.as_ref().map(|s| s.settings.validate()),
+            ),
+        ];
+        for (name, result) in settings {
+            if let Some(Err(e)) = result {
+                anyhow::bail!("{}.settings: {}", name, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Enabled services and their addresses.
+    pub fn services(&self) -> Vec<(Kind, Vec<Address>)> {
+        self.entries()
+            .into_iter()
+            .filter(|(_, _, enabled, _)| *enabled)
+            .map(|(_, kind, _, listen)| (kind, listen.to_vec()))
+            .collect()
+    }
+
+    pub fn parameters(&self) -> Parameters {
+        fn settings<S: Clone + Default>(section: &Option<Section<S>>) -> S {
+            section
+                .as_ref()
+                .map(|s| s.settings.clone())
+                .unwrap_or_default()
+        }
+
+        Parameters {
+            prime_time: settings(&self.prime_time),
+            budget_chat: settings(&self.budget_chat),
+            unusual_db: settings(&self.unusual_db),
+            mob_in_the_middle: settings(&self.mob_in_the_middle),
+            voracious_code_storage: settings(&self.voracious_code_storage),
+            pest_control: settings(&self.pest_control),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_services_and_settings() {
+        let config = Config::parse(
+            r#"
+            [smoke_test]
+            listen = ["127.0.0.1:8000", "unix:/run/echo.sock"]
+
+            [prime_time]
+            enabled = false
+
+            [budget_chat]
+            listen = ["[::]:8003"]
+
+            [budget_chat.settings]
+            welcome = "Hi, who are you?"
+            max_name_length = 16
+
+            [unusual_db]
+            listen = ["0.0.0.0:8004"]
+            settings = { version = "2.0" }
+            "#,
+        )
+        .expect("valid config");
+
+        assert_eq!(
+            config.services(),
+            vec![
+                (
+                    Kind::SmokeTest,
+                    vec![
+                        "127.0.0.1:8000".parse().unwrap(),
+                        Address::Unix("/run/echo.sock".into())
+                    ]
+                ),
+                (Kind::BudgetChat, vec!["[::]:8003".parse().unwrap()]),
+                (Kind::UnusualDb, vec!["0.0.0.0:8004".parse().unwrap()]),
+            ]
+        );
+
+        let parameters = config.parameters();
+        assert_eq!(parameters.budget_chat.welcome, "Hi, who are you?");
+        assert_eq!(parameters.budget_chat.max_name_length, 16);
+        assert_eq!(parameters.budget_chat.broadcast_capacity, 100);
+        assert_eq!(parameters.unusual_db.version, "2.0");
+        assert_eq!(
+            parameters.mob_in_the_middle,
+            mob_in_the_middle::Config::default()
+        );
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let error = |content| Config::parse(content).unwrap_err().to_string();
+
+        assert_eq!(error(""), "no service enabled");
+        assert_eq!(
+            error("[smoke_test]\nenabled = false\nlisten = [\"127.0.0.1:8000\"]"),
+            "no service enabled"
+        );
+        assert_eq!(
+            error("[smoke_test]"),
+            "smoke_test.listen: no address to listen on"
+        );
+        assert_eq!(
+            error(
+                "[budget_chat]\nlisten = [\"127.0.0.1:8003\"]\nsettings = { max_name_length = 0 }"
+            ),
+            "budget_chat.settings: max_name_length must be at least 1"
+        );
+        assert_eq!(
+            error("[prime_time]\nlisten = [\"127.0.0.1:8001\"]\nsettings = { max_digits = 0 }"),
+            "prime_time.settings: max_digits must be at least 1"
+        );
+        assert_eq!(
+            error("[mob_in_the_middle]\nlisten = [\"127.0.0.1:8005\"]\nsettings = { boguscoin = \"1abc\" }"),
+            "mob_in_the_middle.settings: boguscoin `1abc` is not a boguscoin address"
+        );
+
+        // unknown services and keys, and invalid values are reported by the parser
+        assert!(error("[smoke_tset]\nlisten = [\"127.0.0.1:8000\"]").contains("smoke_tset"));
+        assert!(error("[smoke_test]\nlisten = [\"127.0.0.1:8000\"]\nport = 8000").contains("port"));
+        assert!(error("[smoke_test]\nlisten = [\"nowhere\"]").contains("nowhere"));
+        assert!(error(
+            "[smoke_test]\nlisten = [\"127.0.0.1:8000\"]\nsettings = { welcome = \"hi\" }"
+        )
+        .contains("welcome"));
+        assert!(error(
+            "[budget_chat]\nlisten = [\"127.0.0.1:8003\"]\nsettings = { welcom = \"hi\" }"
+        )
+        .contains("welcom"));
+    }
+}
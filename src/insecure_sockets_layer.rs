@@ -0,0 +1,366 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/// Cipher specs longer than this are rejected.
+const MAX_CIPHER_SPEC: usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    ReverseBits,
+    Xor(u8),
+    XorPos,
+    Add(u8),
+    AddPos,
+}
+
+/// Sequence of operations applied to every byte of the stream, depending on
+/// its position in the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cipher {
+    operations: Vec<Operation>,
+}
+
+impl Cipher {
+    /// Reads the cipher spec sent by the client at the start of the session.
+    pub async fn read<R: tokio::io::AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Cipher> {
+        let mut operations = vec![];
+        let mut size = 0;
+
+        loop {
+            size += 1;
+            if size > MAX_CIPHER_SPEC {
+                anyhow::bail!("cipher spec too long");
+            }
+
+            let op = match r.read_u8().await? {
+                0x00 => break,
+                0x01 => Operation::ReverseBits,
+                0x02 => {
+                    size += 1;
+                    Operation::Xor(r.read_u8().await?)
+                }
+                0x03 => Operation::XorPos,
+                0x04 => {
+                    size += 1;
+                    Operation::Add(r.read_u8().await?)
+                }
+                0x05 => Operation::AddPos,
+                op => anyhow::bail!("unknown cipher operation {:#04x}", op),
+            };
+
+            operations.push(op);
+        }
+
+        Ok(Cipher { operations })
+    }
+
+    pub fn encode(&self, byte: u8, pos: u64) -> u8 {
+        let pos = pos as u8;
+
+        self.operations.iter().fold(byte, |b, op| match op {
+            Operation::ReverseBits => b.reverse_bits(),
+            Operation::Xor(n) => b ^ n,
+            Operation::XorPos => b ^ pos,
+            Operation::Add(n) => b.wrapping_add(*n),
+            Operation::AddPos => b.wrapping_add(pos),
+        })
+    }
+
+    pub fn decode(&self, byte: u8, pos: u64) -> u8 {
+        let pos = pos as u8;
+
+        self.operations.iter().rev().fold(byte, |b, op| match op {
+            Operation::ReverseBits => b.reverse_bits(),
+            Operation::Xor(n) => b ^ n,
+            Operation::XorPos => b ^ pos,
+            Operation::Add(n) => b.wrapping_sub(*n),
+            Operation::AddPos => b.wrapping_sub(pos),
+        })
+    }
+
+    /// A cipher that leaves every byte unchanged, at any position.
+    pub fn is_noop(&self) -> bool {
+        // positions only matter modulo 256
+        (0..=255u64).all(|pos| (0..=255u8).all(|b| self.encode(b, pos) == b))
+    }
+}
+
+/// Stream that encodes what is written and decodes what is read with a
+/// cipher, keeping a separate position for each direction.
+pub struct CipherStream<S = tokio::net::TcpStream> {
+    inner: S,
+    cipher: Cipher,
+    read_pos: u64,
+    write_pos: u64,
+}
+
+impl<S> CipherStream<S> {
+    pub fn new(inner: S, cipher: Cipher) -> Self {
+        Self {
+            inner,
+            cipher,
+            read_pos: 0,
+            write_pos: 0,
+        }
+    }
+}
+
+impl<S: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for CipherStream<S> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+
+        std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        for b in &mut buf.filled_mut()[start..] {
+            *b = this.cipher.decode(*b, this.read_pos);
+            this.read_pos += 1;
+        }
+
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for CipherStream<S> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        // the encoding depends on the position, so only the bytes actually
+        // written move it forward
+        let encoded: Vec<u8> = buf
+            .iter()
+            .zip(this.write_pos..)
+            .map(|(&b, pos)| this.cipher.encode(b, pos))
+            .collect();
+
+        let written =
+            std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_write(cx, &encoded))?;
+        this.write_pos += written as u64;
+
+        std::task::Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Problem 8: finds the most wanted toy, over an obfuscated stream.
+pub struct InsecureSocketsLayer;
+
+impl crate::service::Service for InsecureSocketsLayer {
+    fn name(&self) -> &'static str {
+        "insecure_sockets_layer"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(handler(stream))
+    }
+}
+
+/// Serves a single client until it closes the connection.
+pub async fn handler<S>(stream: S) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    // the buffered reader is kept under the cipher stream, it may already
+    // hold encoded bytes sent right after the cipher spec
+    let mut bf = tokio::io::BufReader::new(stream);
+
+    let cipher = Cipher::read(&mut bf).await?;
+    if cipher.is_noop() {
+        tracing::warn!("no-op cipher {:?}, disconnecting", cipher);
+        return Ok(());
+    }
+
+    let (r, mut w) = tokio::io::split(CipherStream::new(bf, cipher));
+    let mut bf = tokio::io::BufReader::new(r);
+    let mut buffer = vec![];
+
+    loop {
+        buffer.clear();
+        if bf.read_until(b'\n', &mut buffer).await? == 0 {
+            break;
+        }
+
+        let request = String::from_utf8_lossy(&buffer);
+        let toy = most_copies(request.trim_end_matches('\n'))
+            .ok_or_else(|| anyhow::anyhow!("invalid request {:?}", request))?;
+
+        tracing::info!("{:?} -> {:?}", request, toy);
+        w.write_all(toy.as_bytes()).await?;
+        w.write_u8(b'\n').await?;
+    }
+
+    w.flush().await?;
+    w.shutdown().await?;
+
+    Ok(())
+}
+
+/// Finds the toy with most copies in a request like `10x toy car,15x dog`.
+pub fn most_copies(request: &str) -> Option<&str> {
+    request
+        .split(',')
+        .map(|toy| {
+            let (copies, _) = toy.split_once('x')?;
+            Some((copies.parse::<u64>().ok()?, toy))
+        })
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max_by_key(|(copies, _)| *copies)
+        .map(|(_, toy)| toy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{timeout, TestServer};
+
+    async fn cipher(spec: &[u8]) -> anyhow::Result<Cipher> {
+        let mut r = spec;
+        Cipher::read(&mut r).await
+    }
+
+    #[tokio::test]
+    async fn encode_examples() {
+        let c = cipher(&[0x02, 0x01, 0x01, 0x00]).await.unwrap();
+        let encoded: Vec<u8> = b"hello"
+            .iter()
+            .zip(0..)
+            .map(|(&b, pos)| c.encode(b, pos))
+            .collect();
+        assert_eq!(encoded, [0x96, 0x26, 0xb6, 0xb6, 0x76]);
+
+        let c = cipher(&[0x05, 0x05, 0x00]).await.unwrap();
+        let encoded: Vec<u8> = b"hello"
+            .iter()
+            .zip(0..)
+            .map(|(&b, pos)| c.encode(b, pos))
+            .collect();
+        assert_eq!(encoded, [0x68, 0x67, 0x70, 0x72, 0x77]);
+
+        let decoded: Vec<u8> = encoded
+            .iter()
+            .zip(0..)
+            .map(|(&b, pos)| c.decode(b, pos))
+            .collect();
+        assert_eq!(decoded, b"hello");
+    }
+
+    #[tokio::test]
+    async fn noop_ciphers() {
+        assert!(cipher(&[0x00]).await.unwrap().is_noop());
+        assert!(cipher(&[0x02, 0x00, 0x00]).await.unwrap().is_noop());
+        assert!(cipher(&[0x02, 0xab, 0x02, 0xab, 0x00])
+            .await
+            .unwrap()
+            .is_noop());
+        assert!(cipher(&[0x01, 0x01, 0x00]).await.unwrap().is_noop());
+        assert!(cipher(&[0x03, 0x03, 0x00]).await.unwrap().is_noop());
+        assert!(cipher(&[0x02, 0xa0, 0x02, 0x0b, 0x02, 0xab, 0x00])
+            .await
+            .unwrap()
+            .is_noop());
+
+        assert!(!cipher(&[0x03, 0x00]).await.unwrap().is_noop());
+        assert!(!cipher(&[0x04, 0x01, 0x00]).await.unwrap().is_noop());
+    }
+
+    #[tokio::test]
+    async fn invalid_cipher_spec() {
+        assert!(cipher(&[0x06, 0x00]).await.is_err());
+        assert!(cipher(&[0x02]).await.is_err());
+        assert!(cipher(&[0x01; 100]).await.is_err());
+    }
+
+    #[test]
+    fn toys() {
+        assert_eq!(
+            most_copies("10x toy car,15x dog on a string,4x inflatable motorcycle"),
+            Some("15x dog on a string")
+        );
+        assert_eq!(most_copies("3x rat"), Some("3x rat"));
+        assert_eq!(most_copies("rat"), None);
+    }
+
+    #[tokio::test]
+    async fn example_session() {
+        let server = TestServer::start(InsecureSocketsLayer).await;
+        let mut client = server.binary_client().await;
+
+        client.send(&[0x02, 0x7b, 0x05, 0x01, 0x00]).await;
+
+        client
+            .send(&[
+                0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+            ])
+            .await;
+        assert_eq!(
+            client.recv_exact(7).await,
+            [0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee]
+        );
+
+        client
+            .send(&[
+                0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+            ])
+            .await;
+        assert_eq!(
+            client.recv_exact(7).await,
+            [0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e]
+        );
+    }
+
+    #[tokio::test]
+    async fn cipher_stream_client() {
+        let server = TestServer::start(InsecureSocketsLayer).await;
+
+        let mut stream = timeout(tokio::net::TcpStream::connect(server.addr))
+            .await
+            .expect("connection with local works");
+
+        let spec = [0x04, 0x10, 0x03, 0x01, 0x00];
+        timeout(stream.write_all(&spec)).await.unwrap();
+
+        let mut stream = CipherStream::new(stream, cipher(&spec).await.unwrap());
+        timeout(stream.write_all(b"1x a,2x b/c\n")).await.unwrap();
+
+        let mut bf = tokio::io::BufReader::new(stream);
+        let mut line = String::new();
+        timeout(bf.read_line(&mut line)).await.unwrap();
+        assert_eq!(line, "2x b/c\n");
+    }
+
+    #[tokio::test]
+    async fn noop_cipher_disconnects() {
+        let server = TestServer::start(InsecureSocketsLayer).await;
+        let mut client = server.binary_client().await;
+
+        client.send(&[0x02, 0x00, 0x00]).await;
+        client.send(b"1x a\n").await;
+
+        assert!(client.recv_to_end().await.is_empty());
+    }
+}
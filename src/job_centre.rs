@@ -0,0 +1,497 @@
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+/// A line sent by the client.
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "request", rename_all = "lowercase")]
+pub enum Request {
+    Put {
+        queue: String,
+        job: serde_json::Value,
+        pri: u64,
+    },
+    Get {
+        queues: Vec<String>,
+        #[serde(default)]
+        wait: bool,
+    },
+    Delete {
+        id: u64,
+    },
+    Abort {
+        id: u64,
+    },
+}
+
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Ok,
+    NoJob,
+    Error,
+}
+
+/// Answer to a request, fields not set are not sent.
+#[derive(serde::Serialize, Debug)]
+pub struct Response {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pri: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    pub fn status(status: Status) -> Self {
+        Self {
+            status,
+            id: None,
+            job: None,
+            pri: None,
+            queue: None,
+            error: None,
+        }
+    }
+
+    pub fn error(error: impl ToString) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::status(Status::Error)
+        }
+    }
+}
+
+struct Job {
+    queue: String,
+    job: serde_json::Value,
+    pri: u64,
+
+    /// Client working on the job, `None` while the job waits in its queue.
+    owner: Option<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Ids of the jobs waiting in each queue, highest priority first.
+    /// Entries of deleted jobs are dropped lazily.
+    queues: std::collections::HashMap<String, std::collections::BinaryHeap<(u64, u64)>>,
+
+    jobs: std::collections::HashMap<u64, Job>,
+
+    next_job: u64,
+    next_client: u64,
+}
+
+impl State {
+    fn put(&mut self, queue: String, job: serde_json::Value, pri: u64) -> u64 {
+        let id = self.next_job;
+        self.next_job += 1;
+
+        self.queues
+            .entry(queue.clone())
+            .or_default()
+            .push((pri, id));
+        self.jobs.insert(
+            id,
+            Job {
+                queue,
+                job,
+                pri,
+                owner: None,
+            },
+        );
+
+        id
+    }
+
+    /// Assigns to the client the job with the highest priority among the
+    /// queues, if any.
+    fn get(&mut self, queues: &[String], client: u64) -> Option<(u64, &Job)> {
+        let mut best: Option<(u64, &String)> = None;
+
+        for name in queues {
+            let Some(queue) = self.queues.get_mut(name) else {
+                continue;
+            };
+
+            // drop the deleted jobs
+            while let Some(&(_, id)) = queue.peek() {
+                if self.jobs.contains_key(&id) {
+                    break;
+                }
+
+                queue.pop();
+            }
+
+            if let Some(&(pri, _)) = queue.peek() {
+                if best.is_none_or(|(best_pri, _)| pri > best_pri) {
+                    best = Some((pri, name));
+                }
+            }
+        }
+
+        let (_, name) = best?;
+        let (_, id) = self.queues.get_mut(name)?.pop()?;
+
+        let job = self.jobs.get_mut(&id)?;
+        job.owner = Some(client);
+
+        Some((id, job))
+    }
+
+    fn delete(&mut self, id: u64) -> bool {
+        // the queue entry is dropped the next time the queue is visited
+        self.jobs.remove(&id).is_some()
+    }
+
+    /// Puts a job the client is working on back in its queue.
+    fn abort(&mut self, id: u64, client: u64) -> Status {
+        let Some(job) = self.jobs.get_mut(&id) else {
+            return Status::NoJob;
+        };
+
+        if job.owner != Some(client) {
+            return Status::Error;
+        }
+
+        job.owner = None;
+        self.queues
+            .entry(job.queue.clone())
+            .or_default()
+            .push((job.pri, id));
+
+        Status::Ok
+    }
+
+    /// Puts back in their queues all the jobs of the client.
+    fn release(&mut self, client: u64) {
+        let owned: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.owner == Some(client))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in owned {
+            tracing::info!("job {} back in queue, client {} is gone", id, client);
+            self.abort(id, client);
+        }
+    }
+}
+
+/// Problem 9: a job queue server speaking JSON lines.
+pub struct JobCentre {
+    state: std::sync::Arc<std::sync::Mutex<State>>,
+
+    /// Wakes up the clients waiting for a job.
+    notify: std::sync::Arc<tokio::sync::Notify>,
+}
+
+impl JobCentre {
+    pub fn new() -> Self {
+        Self {
+            state: std::sync::Arc::new(std::sync::Mutex::new(State::default())),
+            notify: std::sync::Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+}
+
+impl Default for JobCentre {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl crate::service::Service for JobCentre {
+    fn name(&self) -> &'static str {
+        "job_centre"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move { self.handler(stream).await })
+    }
+}
+
+/// Gives back the jobs of the client when the connection goes away.
+struct ClientGuard {
+    id: u64,
+    state: std::sync::Arc<std::sync::Mutex<State>>,
+    notify: std::sync::Arc<tokio::sync::Notify>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().release(self.id);
+        self.notify.notify_waiters();
+    }
+}
+
+impl JobCentre {
+    async fn handler(&self, stream: crate::service::Stream) -> anyhow::Result<()> {
+        let client = {
+            let mut state = self.state.lock().unwrap();
+            state.next_client += 1;
+            state.next_client
+        };
+
+        let _guard = ClientGuard {
+            id: client,
+            state: self.state.clone(),
+            notify: self.notify.clone(),
+        };
+
+        let (r, mut w) = tokio::io::split(stream);
+        let mut bf = tokio::io::BufReader::new(r);
+        let mut buffer = vec![];
+
+        loop {
+            buffer.clear();
+            if bf.read_until(b'\n', &mut buffer).await? == 0 {
+                break;
+            }
+
+            let resp = match serde_json::from_slice::<Request>(&buffer) {
+                Ok(req) => {
+                    tracing::info!("client {}: {:?}", client, req);
+                    self.process(req, client).await
+                }
+                Err(e) => Response::error(e),
+            };
+
+            let body = serde_json::to_vec(&resp)?;
+            w.write_all(&body).await?;
+            w.write_u8(b'\n').await?;
+        }
+
+        w.flush().await?;
+        w.shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn process(&self, req: Request, client: u64) -> Response {
+        match req {
+            Request::Put { queue, job, pri } => {
+                let id = self.state.lock().unwrap().put(queue, job, pri);
+                self.notify.notify_waiters();
+
+                Response {
+                    id: Some(id),
+                    ..Response::status(Status::Ok)
+                }
+            }
+
+            Request::Get { queues, wait } => loop {
+                // registered before looking for a job, so a put happening in
+                // between is not missed
+                let notified = self.notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+
+                if let Some((id, job)) = self.state.lock().unwrap().get(&queues, client) {
+                    break Response {
+                        id: Some(id),
+                        job: Some(job.job.clone()),
+                        pri: Some(job.pri),
+                        queue: Some(job.queue.clone()),
+                        ..Response::status(Status::Ok)
+                    };
+                }
+
+                if !wait {
+                    break Response::status(Status::NoJob);
+                }
+
+                notified.await;
+            },
+
+            Request::Delete { id } => match self.state.lock().unwrap().delete(id) {
+                true => Response::status(Status::Ok),
+                false => Response::status(Status::NoJob),
+            },
+
+            Request::Abort { id } => {
+                let status = self.state.lock().unwrap().abort(id, client);
+                if status == Status::Ok {
+                    self.notify.notify_waiters();
+                }
+
+                match status {
+                    Status::Error => Response::error("job not assigned to this client"),
+                    status => Response::status(status),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{LineClient, TestServer};
+
+    struct Client(LineClient);
+
+    impl Client {
+        async fn connect(addr: std::net::SocketAddr) -> Self {
+            Self(LineClient::connect(addr).await)
+        }
+
+        async fn send(&mut self, req: &str) -> serde_json::Value {
+            self.0.send(req).await;
+            self.recv().await
+        }
+
+        async fn recv(&mut self) -> serde_json::Value {
+            let line = self.0.recv().await.expect("response line");
+            serde_json::from_str(&line).expect("valid json response")
+        }
+    }
+
+    #[tokio::test]
+    async fn put_get_delete() {
+        let server = TestServer::start(JobCentre::new()).await;
+        let addr = server.addr;
+        let mut c = Client::connect(addr).await;
+
+        let resp = c
+            .send(r#"{"request":"put","queue":"q1","job":{"title":"low"},"pri":10}"#)
+            .await;
+        assert_eq!(resp["status"], "ok");
+        let low = resp["id"].as_u64().unwrap();
+
+        let resp = c
+            .send(r#"{"request":"put","queue":"q2","job":{"title":"high"},"pri":100}"#)
+            .await;
+        let high = resp["id"].as_u64().unwrap();
+
+        let resp = c.send(r#"{"request":"get","queues":["q1","q2"]}"#).await;
+        assert_eq!(
+            resp,
+            serde_json::json!({"status":"ok","id":high,"job":{"title":"high"},"pri":100,"queue":"q2"})
+        );
+
+        let resp = c
+            .send(&format!(r#"{{"request":"delete","id":{low}}}"#))
+            .await;
+        assert_eq!(resp, serde_json::json!({"status":"ok"}));
+
+        let resp = c
+            .send(&format!(r#"{{"request":"delete","id":{low}}}"#))
+            .await;
+        assert_eq!(resp, serde_json::json!({"status":"no-job"}));
+
+        let resp = c.send(r#"{"request":"get","queues":["q1","q2"]}"#).await;
+        assert_eq!(resp, serde_json::json!({"status":"no-job"}));
+    }
+
+    #[tokio::test]
+    async fn abort_and_disconnect() {
+        let server = TestServer::start(JobCentre::new()).await;
+        let addr = server.addr;
+        let mut alice = Client::connect(addr).await;
+        let mut bob = Client::connect(addr).await;
+
+        let resp = alice
+            .send(r#"{"request":"put","queue":"q","job":1,"pri":1}"#)
+            .await;
+        let id = resp["id"].as_u64().unwrap();
+
+        let resp = alice.send(r#"{"request":"get","queues":["q"]}"#).await;
+        assert_eq!(resp["id"], id);
+
+        // only the client working on the job can abort it
+        let resp = bob
+            .send(&format!(r#"{{"request":"abort","id":{id}}}"#))
+            .await;
+        assert_eq!(resp["status"], "error");
+
+        let resp = alice
+            .send(&format!(r#"{{"request":"abort","id":{id}}}"#))
+            .await;
+        assert_eq!(resp, serde_json::json!({"status":"ok"}));
+
+        let resp = alice.send(r#"{"request":"get","queues":["q"]}"#).await;
+        assert_eq!(resp["id"], id);
+
+        // the job goes back to the queue once alice disconnects
+        drop(alice);
+
+        let resp = bob
+            .send(r#"{"request":"get","queues":["q"],"wait":true}"#)
+            .await;
+        assert_eq!(resp["id"], id);
+    }
+
+    #[tokio::test]
+    async fn waiting_get() {
+        let server = TestServer::start(JobCentre::new()).await;
+        let addr = server.addr;
+        let mut alice = Client::connect(addr).await;
+        let mut bob = Client::connect(addr).await;
+
+        alice
+            .0
+            .send(r#"{"request":"get","queues":["q"],"wait":true}"#)
+            .await;
+
+        let resp = bob
+            .send(r#"{"request":"put","queue":"other","job":"x","pri":1}"#)
+            .await;
+        assert_eq!(resp["status"], "ok");
+
+        let resp = bob
+            .send(r#"{"request":"put","queue":"q","job":"y","pri":1}"#)
+            .await;
+        let id = resp["id"].as_u64().unwrap();
+
+        let resp = alice.recv().await;
+        assert_eq!(resp["id"], id);
+        assert_eq!(resp["job"], "y");
+    }
+
+    #[tokio::test]
+    async fn invalid_requests() {
+        let server = TestServer::start(JobCentre::new()).await;
+        let addr = server.addr;
+        let mut c = Client::connect(addr).await;
+
+        for req in [
+            "not json",
+            r#"{"request":"put","queue":"q","job":{}}"#,
+            r#"{"request":"get"}"#,
+            r#"{"request":"what"}"#,
+            r#"{"request":"delete","id":"1"}"#,
+        ] {
+            let resp = c.send(req).await;
+            assert_eq!(resp["status"], "error", "{}", req);
+        }
+    }
+
+    #[test]
+    fn priority_across_queues() {
+        let mut state = State::default();
+        let a = state.put("a".to_string(), serde_json::Value::Null, 5);
+        let b = state.put("b".to_string(), serde_json::Value::Null, 7);
+        let c = state.put("a".to_string(), serde_json::Value::Null, 9);
+
+        let queues = ["a".to_string(), "b".to_string()];
+        assert_eq!(state.get(&queues, 1).map(|(id, _)| id), Some(c));
+
+        state.delete(b);
+        assert_eq!(state.get(&queues, 1).map(|(id, _)| id), Some(a));
+        assert!(state.get(&queues, 1).is_none());
+
+        state.release(1);
+        assert_eq!(state.get(&queues, 2).map(|(id, _)| id), Some(c));
+    }
+}
@@ -0,0 +1,41 @@
+//! Servers for the [protohackers](https://protohackers.com) exercises.
+//!
+//! Every exercise lives in its own module and exposes a type implementing
+//! [`service::Service`], plus the message types and parsers of its protocol.
+//! A service is served with [`service::Service::run`] on a
+//! [`service::Listener`] bound with the transport it asks for, until the
+//! [`shutdown::Shutdown`] is triggered.
+
+pub mod activation;
+/// 3. Budget Chat
+pub mod budget_chat;
+/// 8. Insecure Sockets Layer
+pub mod insecure_sockets_layer;
+/// 9. Job Centre
+pub mod job_centre;
+pub mod limits;
+/// 7. Line Reversal
+pub mod line_reversal;
+/// 2. Means to an End
+pub mod means_to_an_end;
+pub mod metrics;
+/// 5. Mob in the Middle
+pub mod mob_in_the_middle;
+/// 11. Pest Control
+pub mod pest_control;
+/// 1. Prime Time
+pub mod prime_time;
+pub mod proxy_protocol;
+pub mod service;
+pub mod shutdown;
+/// 0. Smoke Test
+pub mod smoke_test;
+/// 6. Speed Daemon
+pub mod speed_daemon;
+#[cfg(test)]
+mod test_support;
+pub mod timeouts;
+/// 4. Unusual Database Program
+pub mod unusual_db;
+/// 10. Voracious Code Storage
+pub mod voracious_code_storage;
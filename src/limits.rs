@@ -0,0 +1,252 @@
+//! Admission control of the connections: how many can be served at once, by
+//! the same address, and how fast new ones are accepted.
+
+/// Limits shared by all the services of the process, `None` disables them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Max connections served at the same time.
+    pub max_connections: Option<usize>,
+
+    /// Max connections served at the same time for a single client address.
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Max connections accepted per second, with bursts of the same size.
+    pub accept_rate: Option<u32>,
+}
+
+/// Why a connection has been refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+    RateLimited,
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Refusal::TooManyConnections => "too many connections",
+            Refusal::TooManyConnectionsFromIp => "too many connections from your address",
+            Refusal::RateLimited => "too many new connections, retry later",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+/// Token bucket refilled at `rate` tokens per second.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: tokio::time::Instant,
+}
+
+impl Bucket {
+    fn take(&mut self) -> bool {
+        let now = tokio::time::Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Decides whether accepted connections can be served, according to the
+/// [`Limits`].
+pub struct Admission {
+    connections: Option<std::sync::Arc<tokio::sync::Semaphore>>,
+    max_per_ip: Option<usize>,
+    /// Connections being served, by client address.
+    per_ip: PerIp,
+    bucket: Option<std::sync::Mutex<Bucket>>,
+}
+
+impl Admission {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            connections: limits
+                .max_connections
+                .map(|n| std::sync::Arc::new(tokio::sync::Semaphore::new(n))),
+            max_per_ip: limits.max_connections_per_ip,
+            per_ip: Default::default(),
+            bucket: limits.accept_rate.map(|rate| {
+                std::sync::Mutex::new(Bucket {
+                    rate: rate as f64,
+                    tokens: rate as f64,
+                    last: tokio::time::Instant::now(),
+                })
+            }),
+        }
+    }
+
+    /// Admits a connection from `ip`, the returned permit has to be kept
+    /// while the connection is served. Clients without an IP, on unix
+    /// sockets, are not limited per address.
+    pub fn admit(&self, ip: Option<std::net::IpAddr>) -> Result<Permit, Refusal> {
+        if let Some(bucket) = &self.bucket {
+            if !bucket.lock().unwrap().take() {
+                return Err(Refusal::RateLimited);
+            }
+        }
+
+        let connection = match &self.connections {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| Refusal::TooManyConnections)?,
+            ),
+            None => None,
+        };
+
+        let mut permit = Permit {
+            _connection: connection,
+            ip: None,
+        };
+        self.admit_ip(&mut permit, ip)?;
+
+        Ok(permit)
+    }
+
+    /// Checks the limit of `ip` for a connection admitted without knowing
+    /// its address, e.g. before reading its PROXY header.
+    pub fn admit_ip(
+        &self,
+        permit: &mut Permit,
+        ip: Option<std::net::IpAddr>,
+    ) -> Result<(), Refusal> {
+        if let (Some(max), Some(ip)) = (self.max_per_ip, ip) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_default();
+            if *count >= max {
+                return Err(Refusal::TooManyConnectionsFromIp);
+            }
+
+            *count += 1;
+            permit.ip = Some((ip, self.per_ip.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Self::new(Limits::default())
+    }
+}
+
+type PerIp = std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, usize>>>;
+
+/// Place of an admitted connection, released when dropped.
+pub struct Permit {
+    _connection: Option<tokio::sync::OwnedSemaphorePermit>,
+    ip: Option<(std::net::IpAddr, PerIp)>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((ip, per_ip)) = &self.ip {
+            let mut per_ip = per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<std::net::IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn max_connections() {
+        let admission = Admission::new(Limits {
+            max_connections: Some(2),
+            ..Limits::default()
+        });
+
+        let first = admission.admit(ip("10.0.0.1")).unwrap();
+        let _second = admission.admit(ip("10.0.0.2")).unwrap();
+        assert_eq!(
+            admission.admit(ip("10.0.0.3")).err(),
+            Some(Refusal::TooManyConnections)
+        );
+
+        drop(first);
+        assert!(admission.admit(ip("10.0.0.3")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn max_connections_per_ip() {
+        let admission = Admission::new(Limits {
+            max_connections_per_ip: Some(1),
+            ..Limits::default()
+        });
+
+        let first = admission.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            admission.admit(ip("10.0.0.1")).err(),
+            Some(Refusal::TooManyConnectionsFromIp)
+        );
+        let _other = admission.admit(ip("10.0.0.2")).unwrap();
+
+        drop(first);
+        let again = admission.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(admission.per_ip.lock().unwrap().len(), 2);
+
+        // addresses without connections are forgotten
+        drop(again);
+        assert_eq!(admission.per_ip.lock().unwrap().len(), 1);
+
+        // unix clients are not limited
+        let _unix = admission.admit(None).unwrap();
+        assert!(admission.admit(None).is_ok());
+
+        // the address can be known after the admission
+        let mut proxied = admission.admit(None).unwrap();
+        assert_eq!(
+            admission.admit_ip(&mut proxied, ip("10.0.0.2")).err(),
+            Some(Refusal::TooManyConnectionsFromIp)
+        );
+        admission.admit_ip(&mut proxied, ip("10.0.0.3")).unwrap();
+        assert!(admission.admit(ip("10.0.0.3")).is_err());
+
+        drop(proxied);
+        assert!(admission.admit(ip("10.0.0.3")).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_rate() {
+        let admission = Admission::new(Limits {
+            accept_rate: Some(2),
+            ..Limits::default()
+        });
+
+        // the burst is allowed, then one every half a second
+        assert!(admission.admit(ip("10.0.0.1")).is_ok());
+        assert!(admission.admit(ip("10.0.0.1")).is_ok());
+        assert_eq!(
+            admission.admit(ip("10.0.0.1")).err(),
+            Some(Refusal::RateLimited)
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(admission.admit(ip("10.0.0.1")).is_ok());
+        assert!(admission.admit(ip("10.0.0.1")).is_err());
+    }
+}
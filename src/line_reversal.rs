@@ -0,0 +1,921 @@
+/// Packets must be smaller than this, anything bigger is invalid.
+const MAX_PACKET: usize = 1000;
+
+/// Max size of the escaped data carried by a single data message, so that
+/// the whole packet stays below `MAX_PACKET`.
+const MAX_DATA: usize = 900;
+
+/// Numeric fields must be smaller than this.
+const MAX_NUMBER: u32 = 2147483648;
+
+/// Longest line that can be reversed, longer ones close the session.
+const MAX_LINE: usize = 10_000;
+
+/// Default max sessions open at the same time, new ones are closed straight
+/// away.
+const MAX_SESSIONS: usize = 10_000;
+
+/// An LRCP packet.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Connect {
+        session: u32,
+    },
+    Data {
+        session: u32,
+        pos: u32,
+        data: Vec<u8>,
+    },
+    Ack {
+        session: u32,
+        length: u32,
+    },
+    Close {
+        session: u32,
+    },
+}
+
+impl Message {
+    /// Parses a packet, `None` means that the packet is invalid and must be
+    /// ignored.
+    pub fn parse(packet: &[u8]) -> Option<Message> {
+        if packet.len() >= MAX_PACKET {
+            return None;
+        }
+
+        let fields = split_fields(packet)?;
+
+        let msg = match fields.as_slice() {
+            [kind, session] if kind == b"connect" => Message::Connect {
+                session: number(session)?,
+            },
+            [kind, session, pos, data] if kind == b"data" => Message::Data {
+                session: number(session)?,
+                pos: number(pos)?,
+                data: data.clone(),
+            },
+            [kind, session, length] if kind == b"ack" => Message::Ack {
+                session: number(session)?,
+                length: number(length)?,
+            },
+            [kind, session] if kind == b"close" => Message::Close {
+                session: number(session)?,
+            },
+            _ => return None,
+        };
+
+        Some(msg)
+    }
+
+    /// Encodes the message, escaping its data.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::Connect { session } => format!("/connect/{session}/").into_bytes(),
+            Message::Data { session, pos, data } => {
+                let mut packet = format!("/data/{session}/{pos}/").into_bytes();
+                packet.extend_from_slice(&escape(data));
+                packet.push(b'/');
+                packet
+            }
+            Message::Ack { session, length } => format!("/ack/{session}/{length}/").into_bytes(),
+            Message::Close { session } => format!("/close/{session}/").into_bytes(),
+        }
+    }
+}
+
+/// Splits the packet on the unescaped slashes, unescaping the fields.
+fn split_fields(packet: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let inner = packet.strip_prefix(b"/")?;
+
+    let mut fields = vec![];
+    let mut current = vec![];
+    let mut escaped = false;
+
+    for &c in inner {
+        if escaped {
+            if c != b'/' && c != b'\\' {
+                return None;
+            }
+
+            current.push(c);
+            escaped = false;
+        } else if c == b'\\' {
+            escaped = true;
+        } else if c == b'/' {
+            fields.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+
+    // the packet must be terminated by an unescaped slash
+    if escaped || !current.is_empty() {
+        return None;
+    }
+
+    Some(fields)
+}
+
+fn number(field: &[u8]) -> Option<u32> {
+    if field.is_empty() || !field.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    let n = std::str::from_utf8(field).ok()?.parse::<u32>().ok()?;
+    (n < MAX_NUMBER).then_some(n)
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &c in data {
+        if c == b'/' || c == b'\\' {
+            escaped.push(b'\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Splits the data in chunks that fit in a single data message once escaped.
+fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut size = 0;
+
+    for (i, &c) in data.iter().enumerate() {
+        let escaped_size = if c == b'/' || c == b'\\' { 2 } else { 1 };
+        if size + escaped_size > MAX_DATA {
+            chunks.push(&data[start..i]);
+            start = i;
+            size = 0;
+        }
+
+        size += escaped_size;
+    }
+
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+
+    chunks
+}
+
+struct Session {
+    id: u32,
+    peer: std::net::SocketAddr,
+
+    /// Bytes received from the peer, in order.
+    received: usize,
+
+    /// Received bytes that are not a complete line yet.
+    line: Vec<u8>,
+
+    /// Bytes sent to the peer and not acknowledged yet, the ones before are
+    /// forgotten.
+    sent: Vec<u8>,
+
+    /// Bytes acknowledged by the peer, the position of `sent` in the stream.
+    acked: usize,
+
+    /// When data has been sent the last time.
+    last_send: tokio::time::Instant,
+
+    /// When the peer sent a message the last time.
+    last_seen: tokio::time::Instant,
+
+    /// Since when the peer has not acknowledged anything new while having
+    /// data still to acknowledge.
+    waiting_since: tokio::time::Instant,
+}
+
+impl Session {
+    fn new(id: u32, peer: std::net::SocketAddr, now: tokio::time::Instant) -> Self {
+        Self {
+            id,
+            peer,
+            received: 0,
+            line: vec![],
+            sent: vec![],
+            acked: 0,
+            last_send: now,
+            last_seen: now,
+            waiting_since: now,
+        }
+    }
+
+    /// Bytes sent to the peer since the start of the session.
+    fn sent_length(&self) -> usize {
+        self.acked + self.sent.len()
+    }
+
+    /// Handles received data, `None` means that the session must be closed.
+    fn on_data(
+        &mut self,
+        pos: u32,
+        data: &[u8],
+        now: tokio::time::Instant,
+    ) -> Option<Vec<Message>> {
+        let pos = pos as usize;
+        self.last_seen = now;
+
+        // data after a hole, the peer has to retransmit what is missing
+        if pos > self.received {
+            return Some(vec![self.ack()]);
+        }
+
+        // only the part never seen before is new
+        let new = data.get(self.received - pos..).unwrap_or_default();
+        if new.is_empty() {
+            return Some(vec![self.ack()]);
+        }
+
+        self.received += new.len();
+        self.line.extend_from_slice(new);
+
+        let mut msgs = vec![self.ack()];
+
+        let mut output = vec![];
+        while let Some(i) = self.line.iter().position(|&c| c == b'\n') {
+            let mut line: Vec<u8> = self.line.drain(..=i).collect();
+            line.pop();
+            line.reverse();
+            line.push(b'\n');
+            output.extend(line);
+        }
+
+        if self.line.len() > MAX_LINE {
+            return None;
+        }
+
+        if !output.is_empty() {
+            if self.sent.is_empty() {
+                self.waiting_since = now;
+            }
+
+            let from = self.sent_length();
+            self.sent.extend(output);
+            msgs.extend(self.data_from(from));
+            self.last_send = now;
+        }
+
+        Some(msgs)
+    }
+
+    /// Handles an ack, `None` means that the session must be closed.
+    fn on_ack(&mut self, length: u32, now: tokio::time::Instant) -> Option<Vec<Message>> {
+        let length = length as usize;
+        self.last_seen = now;
+
+        if length <= self.acked {
+            return Some(vec![]);
+        }
+
+        // the peer is misbehaving, acknowledging data never sent
+        if length > self.sent_length() {
+            return None;
+        }
+
+        self.sent.drain(..length - self.acked);
+        self.acked = length;
+        self.waiting_since = now;
+
+        if !self.sent.is_empty() {
+            self.last_send = now;
+            return Some(self.data_from(length));
+        }
+
+        Some(vec![])
+    }
+
+    /// Retransmits the unacknowledged data, `None` means that the session
+    /// expired: the peer has been silent, or has not acknowledged the data,
+    /// for `expiry`.
+    fn on_tick(
+        &mut self,
+        now: tokio::time::Instant,
+        retransmit: std::time::Duration,
+        expiry: std::time::Duration,
+    ) -> Option<Vec<Message>> {
+        if now.duration_since(self.last_seen) >= expiry {
+            return None;
+        }
+
+        if self.sent.is_empty() {
+            return Some(vec![]);
+        }
+
+        if now.duration_since(self.waiting_since) >= expiry {
+            return None;
+        }
+
+        if now.duration_since(self.last_send) < retransmit {
+            return Some(vec![]);
+        }
+
+        self.last_send = now;
+        Some(self.data_from(self.acked))
+    }
+
+    fn ack(&self) -> Message {
+        Message::Ack {
+            session: self.id,
+            length: self.received as u32,
+        }
+    }
+
+    /// Data messages of the bytes sent from `from`, that must not be
+    /// acknowledged yet.
+    fn data_from(&self, from: usize) -> Vec<Message> {
+        let mut pos = from;
+
+        chunks(&self.sent[from - self.acked..])
+            .into_iter()
+            .map(|chunk| {
+                let msg = Message::Data {
+                    session: self.id,
+                    pos: pos as u32,
+                    data: chunk.to_vec(),
+                };
+                pos += chunk.len();
+                msg
+            })
+            .collect()
+    }
+}
+
+/// Problem 7: reverses lines sent over LRCP, a reliable protocol on UDP.
+pub struct LineReversal {
+    retransmit: std::time::Duration,
+    expiry: std::time::Duration,
+    max_sessions: usize,
+}
+
+impl LineReversal {
+    pub fn new() -> Self {
+        Self::with_timeouts(
+            std::time::Duration::from_secs(3),
+            std::time::Duration::from_secs(60),
+        )
+        .expect("default timeouts are valid")
+    }
+
+    /// `retransmit` is how long to wait for an ack before sending the data
+    /// again, `expiry` is how long to wait for a message or an ack before
+    /// giving up on the session. Both must be at least a millisecond.
+    pub fn with_timeouts(
+        retransmit: std::time::Duration,
+        expiry: std::time::Duration,
+    ) -> anyhow::Result<Self> {
+        if retransmit < std::time::Duration::from_millis(1) {
+            anyhow::bail!("retransmit timeout must be at least 1ms");
+        }
+
+        if expiry < std::time::Duration::from_millis(1) {
+            anyhow::bail!("expiry timeout must be at least 1ms");
+        }
+
+        Ok(Self {
+            retransmit,
+            expiry,
+            max_sessions: MAX_SESSIONS,
+        })
+    }
+
+    /// Sets how many sessions can be open at the same time.
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+}
+
+impl Default for LineReversal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl crate::service::Service for LineReversal {
+    fn name(&self) -> &'static str {
+        "line_reversal"
+    }
+
+    fn transport(&self) -> crate::service::Transport {
+        crate::service::Transport::Udp
+    }
+
+    fn run(
+        self: std::sync::Arc<Self>,
+        listener: crate::service::Listener,
+        _options: crate::service::Options,
+        shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move {
+            match listener {
+                crate::service::Listener::Udp(socket) => self.serve(socket, shutdown).await,
+                _ => {
+                    anyhow::bail!("line_reversal needs a udp socket")
+                }
+            }
+        })
+    }
+}
+
+impl LineReversal {
+    async fn serve(
+        &self,
+        listener: tokio::net::UdpSocket,
+        shutdown: crate::shutdown::Shutdown,
+    ) -> anyhow::Result<()> {
+        let mut sessions = std::collections::HashMap::<u32, Session>::new();
+
+        // sessions are the connections of LRCP
+        let metrics = crate::metrics::ConnectionMetrics::new("line_reversal");
+
+        let period =
+            (self.retransmit.min(self.expiry) / 10).max(std::time::Duration::from_millis(1));
+        let mut ticker = tokio::time::interval(period);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let mut buffer = vec![0; MAX_PACKET];
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => {
+                    tracing::info!("Shutting down");
+                    break;
+                }
+
+                _ = ticker.tick() => {
+                    let now = tokio::time::Instant::now();
+                    let mut expired = vec![];
+
+                    for session in sessions.values_mut() {
+                        match session.on_tick(now, self.retransmit, self.expiry) {
+                            Some(msgs) => send(&listener, session.peer, msgs, &metrics.sent).await,
+                            None => expired.push(session.id),
+                        }
+                    }
+
+                    for id in expired {
+                        tracing::info!("session {} expired", id);
+                        sessions.remove(&id);
+                    }
+                }
+
+                v = listener.recv_from(&mut buffer) => {
+                    let (size, addr) = match v {
+                        Ok(v) => v,
+                        Err(e) => {
+                            tracing::error!("Error receiving from UDP socket: {}", e);
+                            continue;
+                        }
+                    };
+
+                    metrics.received.add(size as u64);
+
+                    let Some(msg) = Message::parse(&buffer[..size]) else {
+                        tracing::warn!("invalid packet from {}: {:?}", addr, String::from_utf8_lossy(&buffer[..size]));
+                        continue;
+                    };
+
+                    tracing::info!("{} -> {:?}", addr, msg);
+
+                    let now = tokio::time::Instant::now();
+                    let msgs = match msg {
+                        Message::Connect { session } => {
+                            let full = sessions.len() >= self.max_sessions;
+                            match sessions.get_mut(&session) {
+                                Some(s) => {
+                                    s.last_seen = now;
+                                    vec![s.ack()]
+                                }
+                                None if full => {
+                                    tracing::warn!("session {} refused: too many sessions", session);
+                                    metrics.refused.inc();
+                                    vec![Message::Close { session }]
+                                }
+                                None => {
+                                    metrics.accepted.inc();
+                                    let s = Session::new(session, addr, now);
+                                    let msgs = vec![s.ack()];
+                                    sessions.insert(session, s);
+                                    msgs
+                                }
+                            }
+                        }
+
+                        Message::Data { session, pos, data } => match sessions.get_mut(&session) {
+                            Some(s) => match s.on_data(pos, &data, now) {
+                                Some(msgs) => msgs,
+                                None => {
+                                    sessions.remove(&session);
+                                    vec![Message::Close { session }]
+                                }
+                            },
+                            None => vec![Message::Close { session }],
+                        },
+
+                        Message::Ack { session, length } => match sessions.get_mut(&session) {
+                            Some(s) => match s.on_ack(length, now) {
+                                Some(msgs) => msgs,
+                                None => {
+                                    sessions.remove(&session);
+                                    vec![Message::Close { session }]
+                                }
+                            },
+                            None => vec![Message::Close { session }],
+                        },
+
+                        Message::Close { session } => {
+                            sessions.remove(&session);
+                            vec![Message::Close { session }]
+                        }
+                    };
+
+                    send(&listener, addr, msgs, &metrics.sent).await;
+                }
+            }
+
+            metrics.active.set(sessions.len() as i64);
+        }
+
+        Ok(())
+    }
+}
+
+async fn send(
+    socket: &tokio::net::UdpSocket,
+    addr: std::net::SocketAddr,
+    msgs: Vec<Message>,
+    sent: &crate::metrics::Counter,
+) {
+    for msg in msgs {
+        tracing::info!("{} <- {:?}", addr, msg);
+
+        match socket.send_to(&msg.encode(), addr).await {
+            Ok(n) => sent.add(n as u64),
+            Err(e) => tracing::error!("Error sending to {}: {}", addr, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{TestServer, UdpClient};
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Message::parse(b"/connect/1234567/"),
+            Some(Message::Connect { session: 1234567 })
+        );
+        assert_eq!(
+            Message::parse(b"/data/1/0/foo\\/bar\\\\baz\n/"),
+            Some(Message::Data {
+                session: 1,
+                pos: 0,
+                data: b"foo/bar\\baz\n".to_vec()
+            })
+        );
+        assert_eq!(
+            Message::parse(b"/ack/1/5/"),
+            Some(Message::Ack {
+                session: 1,
+                length: 5
+            })
+        );
+        assert_eq!(
+            Message::parse(b"/close/1/"),
+            Some(Message::Close { session: 1 })
+        );
+
+        // invalid packets
+        assert_eq!(Message::parse(b"/connect/1"), None);
+        assert_eq!(Message::parse(b"connect/1/"), None);
+        assert_eq!(Message::parse(b"/connect/"), None);
+        assert_eq!(Message::parse(b"/connect/-1/"), None);
+        assert_eq!(Message::parse(b"/connect/2147483648/"), None);
+        assert_eq!(Message::parse(b"/data/1/0/foo/bar/"), None);
+        assert_eq!(Message::parse(b"/data/1/0/foo\\/"), None);
+        assert_eq!(Message::parse(b"/ack/1/"), None);
+        assert_eq!(Message::parse(b"/hello/1/"), None);
+
+        let mut big = b"/data/1/0/".to_vec();
+        big.resize(MAX_PACKET - 1, b'a');
+        big.push(b'/');
+        assert_eq!(Message::parse(&big), None);
+    }
+
+    #[test]
+    fn encode() {
+        let msg = Message::Data {
+            session: 1,
+            pos: 2,
+            data: b"a/b\\c".to_vec(),
+        };
+        assert_eq!(msg.encode(), b"/data/1/2/a\\/b\\\\c/");
+        assert_eq!(Message::parse(&msg.encode()), Some(msg));
+    }
+
+    #[test]
+    fn chunked_data() {
+        let data = vec![b'/'; 1000];
+        let chunks = chunks(&data);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| escape(c).len() <= MAX_DATA));
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn session_reordering() {
+        let now = tokio::time::Instant::now();
+        let mut session = Session::new(1, "127.0.0.1:1".parse().unwrap(), now);
+
+        // second half arrives first, only the duplicate ack is sent
+        assert_eq!(
+            session.on_data(6, b"world\n", now),
+            Some(vec![Message::Ack {
+                session: 1,
+                length: 0
+            }])
+        );
+
+        assert_eq!(
+            session.on_data(0, b"hello ", now),
+            Some(vec![Message::Ack {
+                session: 1,
+                length: 6
+            }])
+        );
+
+        // retransmission overlapping with what is already received
+        assert_eq!(
+            session.on_data(3, b"lo world\n", now),
+            Some(vec![
+                Message::Ack {
+                    session: 1,
+                    length: 12
+                },
+                Message::Data {
+                    session: 1,
+                    pos: 0,
+                    data: b"dlrow olleh\n".to_vec()
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn session_line_too_long() {
+        let now = tokio::time::Instant::now();
+        let mut session = Session::new(1, "127.0.0.1:1".parse().unwrap(), now);
+
+        let chunk = [b'a'; MAX_DATA];
+        let mut pos = 0;
+        while pos + chunk.len() <= MAX_LINE {
+            assert!(session.on_data(pos as u32, &chunk, now).is_some());
+            pos += chunk.len();
+        }
+
+        assert_eq!(session.on_data(pos as u32, &chunk, now), None);
+    }
+
+    #[test]
+    fn session_lost_acks() {
+        let retransmit = std::time::Duration::from_secs(3);
+        let expiry = std::time::Duration::from_secs(60);
+
+        let now = tokio::time::Instant::now();
+        let mut session = Session::new(1, "127.0.0.1:1".parse().unwrap(), now);
+        session.on_data(0, b"abc\ndef\n", now);
+
+        // nothing to retransmit yet
+        let later = now + std::time::Duration::from_secs(1);
+        assert_eq!(session.on_tick(later, retransmit, expiry), Some(vec![]));
+
+        // no ack received, all data is sent again
+        let later = now + retransmit;
+        assert_eq!(
+            session.on_tick(later, retransmit, expiry),
+            Some(vec![Message::Data {
+                session: 1,
+                pos: 0,
+                data: b"cba\nfed\n".to_vec()
+            }])
+        );
+
+        // partial ack, only the missing part is sent
+        assert_eq!(
+            session.on_ack(4, later),
+            Some(vec![Message::Data {
+                session: 1,
+                pos: 4,
+                data: b"fed\n".to_vec()
+            }])
+        );
+
+        // acknowledged data is forgotten
+        assert_eq!(session.sent, b"fed\n");
+
+        // duplicated ack is ignored
+        assert_eq!(session.on_ack(2, later), Some(vec![]));
+
+        // the peer acknowledges data never sent
+        assert_eq!(session.on_ack(100, later), None);
+    }
+
+    #[test]
+    fn session_expiry() {
+        let retransmit = std::time::Duration::from_secs(3);
+        let expiry = std::time::Duration::from_secs(60);
+
+        let now = tokio::time::Instant::now();
+        let mut session = Session::new(1, "127.0.0.1:1".parse().unwrap(), now);
+        session.on_data(0, b"abc\n", now);
+
+        let later = now + expiry;
+        assert_eq!(session.on_tick(later, retransmit, expiry), None);
+    }
+
+    #[test]
+    fn idle_session_expiry() {
+        let retransmit = std::time::Duration::from_secs(3);
+        let expiry = std::time::Duration::from_secs(60);
+
+        let now = tokio::time::Instant::now();
+        let mut session = Session::new(1, "127.0.0.1:1".parse().unwrap(), now);
+        session.on_data(0, b"abc\n", now);
+        session.on_ack(4, now);
+
+        // nothing to acknowledge, but messages keep the session alive
+        let later = now + expiry / 2;
+        assert_eq!(session.on_ack(4, later), Some(vec![]));
+        assert_eq!(
+            session.on_tick(now + expiry, retransmit, expiry),
+            Some(vec![])
+        );
+
+        assert_eq!(session.on_tick(later + expiry, retransmit, expiry), None);
+    }
+
+    #[test]
+    fn invalid_timeouts() {
+        let second = std::time::Duration::from_secs(1);
+        assert!(LineReversal::with_timeouts(std::time::Duration::ZERO, second).is_err());
+        assert!(LineReversal::with_timeouts(second, std::time::Duration::ZERO).is_err());
+        assert!(LineReversal::with_timeouts(second, second).is_ok());
+    }
+
+    async fn recv(client: &UdpClient) -> Message {
+        Message::parse(&client.recv().await).expect("valid message")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_client() {
+        let server = TestServer::start(
+            LineReversal::with_timeouts(
+                std::time::Duration::from_millis(100),
+                std::time::Duration::from_millis(1000),
+            )
+            .unwrap(),
+        )
+        .await;
+        let client = server.udp_client().await;
+
+        client.send(b"/connect/42/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 42,
+                length: 0
+            }
+        );
+
+        // the first packet is lost, the second one arrives
+        client.send(b"/data/42/3/lo\\/\n/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 42,
+                length: 0
+            }
+        );
+
+        // the first packet is retransmitted
+        client.send(b"/data/42/0/hel/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 42,
+                length: 3
+            }
+        );
+
+        // invalid packets are ignored
+        client.send(b"/data/42/3/l/o\n/").await;
+
+        // followed by the second one again
+        client.send(b"/data/42/3/lo\\/\n/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 42,
+                length: 7
+            }
+        );
+
+        let reversed = Message::Data {
+            session: 42,
+            pos: 0,
+            data: b"/olleh\n".to_vec(),
+        };
+        assert_eq!(recv(&client).await, reversed);
+
+        // the ack is lost, so the server retransmits the data
+        assert_eq!(recv(&client).await, reversed);
+
+        client.send(b"/ack/42/7/").await;
+
+        client.send(b"/close/42/").await;
+        assert_eq!(recv(&client).await, Message::Close { session: 42 });
+
+        // the session is gone
+        client.send(b"/data/42/7/foo/").await;
+        assert_eq!(recv(&client).await, Message::Close { session: 42 });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_session() {
+        let server = TestServer::start(
+            LineReversal::with_timeouts(
+                std::time::Duration::from_millis(100),
+                std::time::Duration::from_millis(1000),
+            )
+            .unwrap(),
+        )
+        .await;
+        let client = server.udp_client().await;
+
+        client.send(b"/connect/7/").await;
+        recv(&client).await;
+
+        client.send(b"/data/7/0/abc\n/").await;
+        recv(&client).await;
+
+        // never acknowledge the data, the server retransmits it until the
+        // session expires
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+
+        client.send(b"/ack/7/4/").await;
+        loop {
+            match recv(&client).await {
+                Message::Data { .. } => continue,
+                msg => {
+                    assert_eq!(msg, Message::Close { session: 7 });
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_sessions() {
+        let server = TestServer::start(LineReversal::new().with_max_sessions(1)).await;
+        let client = server.udp_client().await;
+
+        client.send(b"/connect/1/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 1,
+                length: 0
+            }
+        );
+
+        client.send(b"/connect/2/").await;
+        assert_eq!(recv(&client).await, Message::Close { session: 2 });
+
+        // the open session can still connect again
+        client.send(b"/connect/1/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 1,
+                length: 0
+            }
+        );
+
+        // idle sessions expire, making room for new ones
+        tokio::time::sleep(std::time::Duration::from_secs(61)).await;
+        client.send(b"/connect/2/").await;
+        assert_eq!(
+            recv(&client).await,
+            Message::Ack {
+                session: 2,
+                length: 0
+            }
+        );
+    }
+}
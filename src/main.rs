@@ -1,83 +1,331 @@
-mod budget_chat;
-mod means_to_an_end;
-mod mob_in_the_middle;
-mod prime_time;
-mod smoke_test;
-mod unusual_db;
-
-use std::io::BufRead;
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-
-    let input = std::io::stdin();
-    println!("select which exercise to run: ");
-    println!("0. Smoke Test");
-    println!("1. Prime Time");
-    println!("2. Means to an End");
-    println!("3. Budget Chat");
-    println!("4. Unusual Database Program");
-    println!("5. Mob in the Middle");
-
-    let selected_exercise = input.lock().lines().next().unwrap()?;
-
-    if &selected_exercise == "4" {
-        unusual_db::run().await?;
-        return Ok(());
+mod cli;
+mod config;
+
+use clap::Parser;
+use protohakers::{
+    budget_chat, insecure_sockets_layer, job_centre, line_reversal, means_to_an_end,
+    mob_in_the_middle, pest_control, prime_time, service, shutdown, smoke_test, speed_daemon,
+    unusual_db, voracious_code_storage,
+};
+use tracing::Instrument;
+
+type Factory = fn(&config::Parameters) -> std::sync::Arc<dyn service::Service>;
+
+/// All the exercises that can be served.
+const SERVICES: &[(cli::Kind, Factory)] = &[
+    (cli::Kind::SmokeTest, |_| {
+        std::sync::Arc::new(smoke_test::SmokeTest)
+    }),
+    (cli::Kind::PrimeTime, |parameters| {
+        std::sync::Arc::new(prime_time::PrimeTime::with_config(
+            parameters.prime_time.clone(),
+        ))
+    }),
+    (cli::Kind::MeansToAnEnd, |_| {
+        std::sync::Arc::new(means_to_an_end::MeansToAnEnd)
+    }),
+    (cli::Kind::BudgetChat, |parameters| {
+        std::sync::Arc::new(budget_chat::BudgetChat::with_config(
+            parameters.budget_chat.clone(),
+        ))
+    }),
+    (cli::Kind::UnusualDb, |parameters| {
+        std::sync::Arc::new(unusual_db::UnusualDb::with_config(
+            parameters.unusual_db.clone(),
+        ))
+    }),
+    (cli::Kind::MobInTheMiddle, |parameters| {
+        std::sync::Arc::new(mob_in_the_middle::MobInTheMiddle::with_config(
+            parameters.mob_in_the_middle.clone(),
+        ))
+    }),
+    (cli::Kind::SpeedDaemon, |_| {
+        std::sync::Arc::new(speed_daemon::SpeedDaemon::new())
+    }),
+    (cli::Kind::LineReversal, |_| {
+        std::sync::Arc::new(line_reversal::LineReversal::new())
+    }),
+    (cli::Kind::InsecureSocketsLayer, |_| {
+        std::sync::Arc::new(insecure_sockets_layer::InsecureSocketsLayer)
+    }),
+    (cli::Kind::JobCentre, |_| {
+        std::sync::Arc::new(job_centre::JobCentre::new())
+    }),
+    (cli::Kind::VoraciousCodeStorage, |parameters| {
+        std::sync::Arc::new(voracious_code_storage::VoraciousCodeStorage::with_config(
+            parameters.voracious_code_storage.clone(),
+        ))
+    }),
+    (cli::Kind::PestControl, |parameters| {
+        std::sync::Arc::new(pest_control::PestControl::with_config(
+            parameters.pest_control.clone(),
+        ))
+    }),
+];
+
+fn main() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
+
+    init_logging(cli.log_format);
+
+    // the variables of the inherited sockets are cleared before the runtime
+    // starts its threads, that could read the environment at the same time,
+    // and a lone inherited socket is only meant for a single exercise
+    let single_service = !matches!(
+        cli.exercise,
+        cli::Exercise::Multi { .. } | cli::Exercise::Config { .. }
+    );
+    let inherited = protohakers::activation::Inherited::from_env(single_service)?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli, inherited))
+}
+
+async fn run(cli: cli::Cli, inherited: protohakers::activation::Inherited) -> anyhow::Result<()> {
+    // the config file is validated before anything is started
+    let config = match &cli.exercise {
+        cli::Exercise::Config { path, check } => {
+            let config = config::Config::load(path)?;
+            if *check {
+                for (kind, addresses) in config.services() {
+                    let addresses: Vec<_> = addresses.iter().map(|a| a.to_string()).collect();
+                    tracing::info!("{:?} on {}", kind, addresses.join(", "));
+                }
+
+                return Ok(());
+            }
+
+            Some(config)
+        }
+        _ => None,
+    };
+
+    // and so are the settings given on the command line
+    let parameters = std::sync::Arc::new(match &config {
+        Some(config) => config.parameters(),
+        None => cli.services.parameters()?,
+    });
+
+    let shutdown = shutdown::Shutdown::new(std::time::Duration::from_secs(cli.drain_timeout));
+    {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            match shutdown::signal().await {
+                Ok(_) => tracing::info!("shutdown signal received"),
+                Err(e) => tracing::error!("unable to listen for shutdown signal: {}", e),
+            }
+
+            shutdown.trigger();
+        });
+    }
+
+    if let Some(address) = cli.metrics_address {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        tracing::info!("metrics listening on {}", listener.local_addr()?);
+
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = protohakers::metrics::serve(listener, shutdown).await {
+                tracing::error!("metrics endpoint stopped with error: {}", e);
+            }
+        });
     }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await?;
-    tracing::info!("listening on {}", listener.local_addr()?);
-
-    if &selected_exercise == "3" {
-        budget_chat::run(listener).await?;
-    } else if &selected_exercise == "5" {
-        let chat_address = "chat.protohackers.com:16963";
-        let boguscoin = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
-        mob_in_the_middle::run(listener, chat_address, boguscoin).await?;
-    } else {
-        let handler = chooser(selected_exercise.parse()?);
-        loop {
-            let stream = match listener.accept().await {
-                Ok((stream, address)) => {
-                    tracing::info!("connection received for {}", address);
-
-                    stream
+    let inherited = std::sync::Arc::new(inherited);
+
+    let unix_socket_mode = cli.unix_socket_mode;
+    let options = service::Options {
+        timeouts: cli.timeouts(),
+        admission: std::sync::Arc::new(protohakers::limits::Admission::new(cli.limits())),
+        proxy_protocol: cli.proxy_protocol,
+        trusted_proxies: cli.trusted_proxy.clone(),
+    };
+    if cli.proxy_protocol == protohakers::proxy_protocol::Mode::Optional
+        && cli.trusted_proxy.is_empty()
+    {
+        tracing::warn!("optional PROXY headers are read from any client, see --trusted-proxy");
+    }
+
+    let kind = match &cli.exercise {
+        cli::Exercise::SmokeTest => cli::Kind::SmokeTest,
+        cli::Exercise::PrimeTime => cli::Kind::PrimeTime,
+        cli::Exercise::MeansToAnEnd => cli::Kind::MeansToAnEnd,
+        cli::Exercise::BudgetChat => cli::Kind::BudgetChat,
+        cli::Exercise::UnusualDb => cli::Kind::UnusualDb,
+        cli::Exercise::MobInTheMiddle => cli::Kind::MobInTheMiddle,
+        cli::Exercise::SpeedDaemon => cli::Kind::SpeedDaemon,
+        cli::Exercise::LineReversal => cli::Kind::LineReversal,
+        cli::Exercise::InsecureSocketsLayer => cli::Kind::InsecureSocketsLayer,
+        cli::Exercise::JobCentre => cli::Kind::JobCentre,
+        cli::Exercise::VoraciousCodeStorage => cli::Kind::VoraciousCodeStorage,
+        cli::Exercise::PestControl => cli::Kind::PestControl,
+        cli::Exercise::Multi { serve: servers } => {
+            // an exercise served on several addresses is a single service
+            let mut grouped: Vec<(cli::Kind, Vec<service::Address>)> = vec![];
+            for (kind, listen) in servers.iter().cloned() {
+                let address = cli.resolve(&listen);
+                match grouped.iter_mut().find(|(k, _)| *k == kind) {
+                    Some((_, addresses)) => addresses.push(address),
+                    None => grouped.push((kind, vec![address])),
                 }
-                Err(e) => return Err(e.into()),
-            };
+            }
+
+            serve_all(
+                grouped,
+                parameters,
+                inherited,
+                unix_socket_mode,
+                options,
+                shutdown,
+            )
+            .await;
+
+            return Ok(());
+        }
+        cli::Exercise::Config { .. } => {
+            let config = config.expect("config is loaded");
+            serve_all(
+                config.services(),
+                parameters,
+                inherited,
+                unix_socket_mode,
+                options,
+                shutdown,
+            )
+            .await;
 
-            tokio::spawn(async move {
-                match handler(stream).await {
+            return Ok(());
+        }
+    };
+
+    serve(
+        kind,
+        &cli.addresses(),
+        &parameters,
+        &inherited,
+        unix_socket_mode,
+        options,
+        shutdown,
+    )
+    .await
+}
+
+/// Installs the global subscriber, levels are taken from `RUST_LOG`.
+fn init_logging(format: cli::LogFormat) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        cli::LogFormat::Text => builder.init(),
+        cli::LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Serves several exercises at once until they all stop.
+async fn serve_all(
+    services: Vec<(cli::Kind, Vec<service::Address>)>,
+    parameters: std::sync::Arc<config::Parameters>,
+    inherited: std::sync::Arc<protohakers::activation::Inherited>,
+    unix_socket_mode: Option<u32>,
+    options: service::Options,
+    shutdown: shutdown::Shutdown,
+) {
+    let mut set = tokio::task::JoinSet::new();
+
+    for (kind, addresses) in services {
+        let span = tracing::info_span!("server", exercise = ?kind);
+
+        let parameters = parameters.clone();
+        let options = options.clone();
+        let inherited = inherited.clone();
+        let shutdown = shutdown.clone();
+
+        // a failing server is only logged, the others keep running
+        set.spawn(
+            async move {
+                match serve(
+                    kind,
+                    &addresses,
+                    &parameters,
+                    &inherited,
+                    unix_socket_mode,
+                    options,
+                    shutdown,
+                )
+                .await
+                {
                     Ok(_) => (),
-                    Err(e) => tracing::error!("error on handling connection: {}", e),
+                    Err(e) => tracing::error!("server stopped with error: {}", e),
                 }
-            });
-        }
+            }
+            .instrument(span),
+        );
     }
 
-    Ok(())
+    while let Some(res) = set.join_next().await {
+        if let Err(e) = res {
+            tracing::error!("server task failed: {}", e);
+        }
+    }
 }
 
-fn chooser(
-    input: u32,
-) -> fn(
-    tokio::net::TcpStream,
-) -> std::pin::Pin<
-    std::boxed::Box<dyn std::future::Future<Output = anyhow::Result<()>> + Send + 'static>,
-> {
-    match input {
-        0 => |s| Box::pin(smoke_test::handler(s)),
-        1 => |s| Box::pin(prime_time::handler(s)),
-        2 => |s| Box::pin(means_to_an_end::handler(s)),
-        _ => {
-            tracing::error!("invalid selection");
-            panic!("invalid selection");
+/// Serves the exercise on all the addresses, or on the socket inherited from
+/// the service manager if there is one.
+async fn serve(
+    kind: cli::Kind,
+    addresses: &[service::Address],
+    parameters: &config::Parameters,
+    inherited: &protohakers::activation::Inherited,
+    unix_socket_mode: Option<u32>,
+    options: service::Options,
+    shutdown: shutdown::Shutdown,
+) -> anyhow::Result<()> {
+    let (_, factory) = SERVICES
+        .iter()
+        .find(|(k, _)| *k == kind)
+        .ok_or_else(|| anyhow::anyhow!("no service registered for {:?}", kind))?;
+
+    let service = factory(parameters);
+    // sockets passed by the service manager take precedence over the addresses
+    let listeners = match inherited.take(service.name(), service.transport())? {
+        Some(listener) => {
+            tracing::info!("{} using inherited socket", service.name());
+            vec![listener]
+        }
+        None => {
+            let mut listeners = vec![];
+            for address in addresses {
+                let listener =
+                    service::Listener::bind(service.transport(), address.clone()).await?;
+                if let Some(mode) = unix_socket_mode {
+                    listener.set_permissions(mode)?;
+                }
+
+                listeners.push(listener);
+            }
+
+            listeners
         }
+    };
+
+    let mut set = tokio::task::JoinSet::new();
+    for listener in listeners {
+        tracing::info!("{} listening on {}", service.name(), listener.local_addr()?);
+        set.spawn(
+            service
+                .clone()
+                .run(listener, options.clone(), shutdown.clone())
+                .in_current_span(),
+        );
+    }
+
+    // the first failure stops the service on all the addresses
+    while let Some(res) = set.join_next().await {
+        res??;
     }
+
+    Ok(())
 }
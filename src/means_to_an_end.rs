@@ -1,7 +1,53 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
-    let (r, mut w) = stream.split();
+/// Problem 2: stores timestamped prices and answers mean queries.
+pub struct MeansToAnEnd;
+
+struct Metrics {
+    inserts: crate::metrics::Counter,
+    queries: crate::metrics::Counter,
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: std::sync::OnceLock<Metrics> = std::sync::OnceLock::new();
+    METRICS.get_or_init(|| {
+        let r = crate::metrics::registry();
+        Metrics {
+            inserts: r.counter(
+                "means_to_an_end_inserts_total",
+                "Prices inserted.",
+                "means_to_an_end",
+            ),
+            queries: r.counter(
+                "means_to_an_end_queries_total",
+                "Mean queries answered.",
+                "means_to_an_end",
+            ),
+        }
+    })
+}
+
+impl crate::service::Service for MeansToAnEnd {
+    fn name(&self) -> &'static str {
+        "means_to_an_end"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(handler(stream))
+    }
+}
+
+/// Serves a single client until it closes the connection.
+pub async fn handler<S>(stream: S) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (r, mut w) = tokio::io::split(stream);
+    let metrics = metrics();
     let mut bf = tokio::io::BufReader::new(r);
 
     let mut db_memory = std::collections::BTreeMap::new();
@@ -19,6 +65,7 @@ pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
                 let price = i32::from_be_bytes(p);
 
                 db_memory.insert(timestamp, price);
+                metrics.inserts.inc();
                 tracing::info!("inserted {} {}", timestamp, price);
             }
             b'Q' => {
@@ -26,6 +73,7 @@ pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
                 let left = i32::from_be_bytes(l);
                 let r: [u8; 4] = action[5..=8].try_into()?;
                 let right = i32::from_be_bytes(r);
+                metrics.queries.inc();
 
                 if left > right {
                     tracing::warn!("left is greater than right");
@@ -61,95 +109,36 @@ pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::test_support::TestServer;
+
+    fn message(kind: u8, a: i32, b: i32) -> Vec<u8> {
+        let mut buffer = vec![kind];
+        buffer.extend_from_slice(&a.to_be_bytes());
+        buffer.extend_from_slice(&b.to_be_bytes());
+        buffer
+    }
 
     #[tokio::test]
     async fn range_left_greather_right() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
-
-        let local_addr = listener.local_addr().expect("local address works");
+        let server = TestServer::start(MeansToAnEnd).await;
+        let mut client = server.binary_client().await;
 
-        tokio::spawn(async move {
-            loop {
-                let stream = match listener.accept().await {
-                    Ok((stream, address)) => {
-                        println!("connection received for {}", address);
+        client.send(&message(b'I', 12345, 101)).await;
+        client.send(&message(b'Q', 20000, 16384)).await;
+        client.shutdown().await;
 
-                        stream
-                    }
-                    Err(_) => panic!("error on accepting connection"),
-                };
-
-                tokio::spawn(handler(stream));
-            }
-        });
-
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
-
-        let (mut r, mut w) = stream.split();
-
-        w.write_u8(b'I').await.unwrap();
-        w.write_i32(12345).await.unwrap();
-        w.write_i32(101).await.unwrap();
-        w.flush().await.expect("to flush msg");
-
-        w.write_u8(b'Q').await.unwrap();
-        w.write_i32(20000).await.unwrap();
-        w.write_i32(16384).await.unwrap();
-        w.flush().await.expect("to flush msg");
-
-        w.shutdown().await.expect("shutdown");
-
-        let resp = r.read_i32().await.expect("reading response");
-        assert_eq!(resp, 0);
+        assert_eq!(client.recv_exact(4).await, 0i32.to_be_bytes());
     }
 
     #[tokio::test]
     async fn example_ok() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
-
-        let local_addr = listener.local_addr().expect("local address works");
-
-        tokio::spawn(async move {
-            loop {
-                let stream = match listener.accept().await {
-                    Ok((stream, address)) => {
-                        println!("connection received for {}", address);
-
-                        stream
-                    }
-                    Err(_) => panic!("error on accepting connection"),
-                };
-
-                tokio::spawn(handler(stream));
-            }
-        });
-
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
-
-        let (mut r, mut w) = stream.split();
-
-        w.write_u8(b'I').await.unwrap();
-        w.write_i32(12345).await.unwrap();
-        w.write_i32(101).await.unwrap();
-        w.flush().await.expect("to flush msg");
-
-        w.write_u8(b'Q').await.unwrap();
-        w.write_i32(12288).await.unwrap();
-        w.write_i32(16384).await.unwrap();
-        w.flush().await.expect("to flush msg");
+        let server = TestServer::start(MeansToAnEnd).await;
+        let mut client = server.binary_client().await;
 
-        w.shutdown().await.expect("shutdown");
+        client.send(&message(b'I', 12345, 101)).await;
+        client.send(&message(b'Q', 12288, 16384)).await;
+        client.shutdown().await;
 
-        let resp = r.read_i32().await.expect("reading response");
-        assert_eq!(resp, 101);
+        assert_eq!(client.recv_exact(4).await, 101i32.to_be_bytes());
     }
 }
@@ -0,0 +1,434 @@
+//! Prometheus metrics of the services.
+//!
+//! Metrics live in a process wide [`Registry`], labelled with the name of the
+//! service they belong to, and are exposed in the text format by [`serve`].
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Prefix of the name of all the metrics.
+const NAMESPACE: &str = "protohackers";
+
+/// Requests bigger than this are rejected, a scrape is a few lines.
+const MAX_REQUEST: usize = 8192;
+
+/// Time given to a client to send its request.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Value that only goes up.
+#[derive(Clone, Debug, Default)]
+pub struct Counter(std::sync::Arc<std::sync::atomic::AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Value that goes up and down.
+#[derive(Clone, Debug, Default)]
+pub struct Gauge(std::sync::Arc<std::sync::atomic::AtomicI64>);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&self) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self.clone())
+    }
+}
+
+pub struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+}
+
+struct Family {
+    help: &'static str,
+    /// Metric of every service, by service name.
+    series: std::collections::BTreeMap<&'static str, Metric>,
+}
+
+/// All the metrics of the process.
+#[derive(Default)]
+pub struct Registry {
+    families: std::sync::Mutex<std::collections::BTreeMap<&'static str, Family>>,
+}
+
+impl Registry {
+    /// Returns the counter `name` of the service, creating it if needed.
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        service: &'static str,
+    ) -> Counter {
+        match self.metric(name, help, service, || Metric::Counter(Counter::default())) {
+            Metric::Counter(c) => c,
+            Metric::Gauge(_) => panic!("metric {name} is a gauge"),
+        }
+    }
+
+    /// Returns the gauge `name` of the service, creating it if needed.
+    pub fn gauge(&self, name: &'static str, help: &'static str, service: &'static str) -> Gauge {
+        match self.metric(name, help, service, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(g) => g,
+            Metric::Counter(_) => panic!("metric {name} is a counter"),
+        }
+    }
+
+    fn metric(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        service: &'static str,
+        new: impl FnOnce() -> Metric,
+    ) -> Metric {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            series: std::collections::BTreeMap::new(),
+        });
+
+        family.series.entry(service).or_insert_with(new).clone()
+    }
+
+    /// Renders all the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.series.values().next() {
+                Some(Metric::Counter(_)) => "counter",
+                Some(Metric::Gauge(_)) => "gauge",
+                None => continue,
+            };
+
+            out.push_str(&format!("# HELP {NAMESPACE}_{name} {}\n", family.help));
+            out.push_str(&format!("# TYPE {NAMESPACE}_{name} {kind}\n"));
+
+            for (service, metric) in &family.series {
+                let value = match metric {
+                    Metric::Counter(c) => c.get().to_string(),
+                    Metric::Gauge(g) => g.get().to_string(),
+                };
+
+                out.push_str(&format!(
+                    "{NAMESPACE}_{name}{{service=\"{service}\"}} {value}\n"
+                ));
+            }
+        }
+
+        out
+    }
+}
+
+/// The registry used by all the services.
+pub fn registry() -> &'static Registry {
+    static REGISTRY: std::sync::OnceLock<Registry> = std::sync::OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// Metrics every service has, updated by the shared accept loop.
+#[derive(Clone)]
+pub struct ConnectionMetrics {
+    pub accepted: Counter,
+    pub refused: Counter,
+    pub active: Gauge,
+    pub received: Counter,
+    pub sent: Counter,
+}
+
+impl ConnectionMetrics {
+    pub fn new(service: &'static str) -> Self {
+        let r = registry();
+
+        Self {
+            accepted: r.counter(
+                "connections_accepted_total",
+                "Connections accepted.",
+                service,
+            ),
+            refused: r.counter(
+                "connections_refused_total",
+                "Connections refused by the limits or for a missing PROXY header.",
+                service,
+            ),
+            active: r.gauge("connections_active", "Connections being served.", service),
+            received: r.counter(
+                "received_bytes_total",
+                "Bytes received from clients.",
+                service,
+            ),
+            sent: r.counter("sent_bytes_total", "Bytes sent to clients.", service),
+        }
+    }
+}
+
+/// Stream counting the bytes going through it.
+pub struct Metered<S> {
+    inner: S,
+    received: Counter,
+    sent: Counter,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, metrics: &ConnectionMetrics) -> Self {
+        Self {
+            inner,
+            received: metrics.received.clone(),
+            sent: metrics.sent.clone(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Metered<S> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+
+        let poll = std::pin::Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(())) = poll {
+            this.received.add((buf.filled().len() - before) as u64);
+        }
+
+        poll
+    }
+}
+
+impl<S: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Metered<S> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        let poll = std::pin::Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = poll {
+            this.sent.add(n as u64);
+        }
+
+        poll
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves `GET /metrics` over HTTP until `shutdown` is triggered.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    shutdown: crate::shutdown::Shutdown,
+) -> anyhow::Result<()> {
+    let mut scrapes = tokio::task::JoinSet::new();
+
+    loop {
+        let (stream, address) = tokio::select! {
+            _ = shutdown.wait() => break,
+
+            Some(_) = scrapes.join_next(), if !scrapes.is_empty() => continue,
+
+            accepted = listener.accept() => accepted?,
+        };
+
+        scrapes.spawn(async move {
+            if let Err(e) = scrape(stream).await {
+                tracing::warn!("metrics request from {} failed: {}", address, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn scrape(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("no request after {:?}", REQUEST_TIMEOUT))??;
+
+    let request = String::from_utf8_lossy(&request);
+    let line = request.lines().next().unwrap_or_default();
+
+    let (status, content_type, body) = match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["GET", "/metrics", _] => ("200 OK", "text/plain; version=0.0.4", registry().render()),
+        ["GET", _, _] => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Reads the request up to the end of the headers.
+async fn read_request(stream: &mut tokio::net::TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+
+    // only the request line matters, the headers are read and ignored
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the end of the request");
+        }
+
+        request.extend_from_slice(&buffer[..n]);
+        if request.len() > MAX_REQUEST {
+            anyhow::bail!("request too big");
+        }
+    }
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let r = Registry::default();
+
+        r.counter("requests_total", "Requests.", "b").add(3);
+        r.counter("requests_total", "Requests.", "a").inc();
+        let members = r.gauge("members", "Members.", "a");
+        members.set(5);
+        drop(members.track());
+
+        assert_eq!(
+            r.render(),
+            "# HELP protohackers_members Members.\n\
+             # TYPE protohackers_members gauge\n\
+             protohackers_members{service=\"a\"} 5\n\
+             # HELP protohackers_requests_total Requests.\n\
+             # TYPE protohackers_requests_total counter\n\
+             protohackers_requests_total{service=\"a\"} 1\n\
+             protohackers_requests_total{service=\"b\"} 3\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_over_http() {
+        let test_counter = registry().counter("scrape_test_total", "Test.", "metrics_test");
+        test_counter.add(42);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("open a listener");
+        let local_addr = listener.local_addr().expect("local address works");
+
+        let shutdown = crate::shutdown::Shutdown::new(std::time::Duration::from_secs(1));
+        let server = tokio::spawn(serve(listener, shutdown.clone()));
+
+        let get = |path: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(local_addr)
+                .await
+                .expect("connection with local works");
+            let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("protohackers_scrape_test_total{service=\"metrics_test\"} 42\n"));
+
+        let response = get("/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.trigger();
+        server
+            .await
+            .expect("server task completes")
+            .expect("server stops cleanly");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("open a listener");
+        let local_addr = listener.local_addr().expect("local address works");
+
+        let shutdown = crate::shutdown::Shutdown::new(std::time::Duration::from_secs(1));
+        tokio::spawn(serve(listener, shutdown.clone()));
+
+        // the connection is closed without a response
+        let mut stream = tokio::net::TcpStream::connect(local_addr)
+            .await
+            .expect("connection with local works");
+        let mut response = vec![];
+        tokio::time::timeout(REQUEST_TIMEOUT * 2, stream.read_to_end(&mut response))
+            .await
+            .expect("the server closes the connection")
+            .unwrap();
+        assert!(response.is_empty());
+
+        shutdown.trigger();
+    }
+}
@@ -1,208 +1,206 @@
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
-pub async fn run(
-    listener: tokio::net::TcpListener,
-    chat_address: &str,
-    boguscoin: &str,
-) -> anyhow::Result<()> {
-    loop {
-        let (stream, address) = listener.accept().await?;
-
-        tracing::info!("connection received for {}", address);
-
-        let chat_address = chat_address.to_string();
-        let boguscoin = boguscoin.to_string();
+/// Settings of the proxy.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address of the upstream budget chat server.
+    pub chat_address: String,
+
+    /// Address that replaces the boguscoins found in the messages.
+    pub boguscoin: String,
+}
 
-        tokio::spawn(async move {
-            match handle(stream, &chat_address, &boguscoin).await {
-                Ok(_) => (),
-                Err(e) => {
-                    tracing::error!("error handling connection from {}: {}", address, e);
-                }
-            }
-        });
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            chat_address: "chat.protohackers.com:16963".to_string(),
+            boguscoin: "7YWHMfk9JZe0LM0g1ZauHuiSxhI".to_string(),
+        }
     }
 }
 
-async fn handle(
-    stream_proxy: tokio::net::TcpStream,
-    chat_address: &str,
-    boguscoin: &str,
-) -> anyhow::Result<()> {
-    let (mut proxy_reader, mut proxy_writer) = {
-        let (r, w) = stream_proxy.into_split();
-        (tokio::io::BufReader::new(r), w)
-    };
-
-    let (mut chat_reader, mut chat_writer) = {
-        let (r, w) = tokio::net::TcpStream::connect(chat_address)
-            .await?
-            .into_split();
-        (tokio::io::BufReader::new(r), w)
-    };
-
-    let boguscoin = boguscoin.to_string();
-    let bog = boguscoin.to_string();
-
-    let chat_to_proxy = tokio::spawn(async move {
-        loop {
-            let mut line = String::new();
-            match chat_reader.read_line(&mut line).await {
-                Ok(0) | Err(_) => break,
-                Ok(_) => {
-                    line.pop();
-                    let resp = replace_message(line, &boguscoin);
-                    tracing::info!("chat -> proxy: {}", resp);
-                    proxy_writer.write_all(resp.as_bytes()).await.unwrap();
-                    proxy_writer.write_u8(b'\n').await.unwrap();
-                }
-            }
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.chat_address.is_empty() {
+            anyhow::bail!("chat_address is empty");
         }
-    });
-
-    let proxy_to_chat = tokio::spawn(async move {
-        loop {
-            let mut line = String::new();
-            match proxy_reader.read_line(&mut line).await {
-                Ok(0) | Err(_) => break,
-                Ok(_) => {
-                    line.pop();
-                    let resp = replace_message(line, &bog);
-                    tracing::info!("proxy -> chat: {}", resp);
-                    chat_writer.write_all(resp.as_bytes()).await.unwrap();
-                    chat_writer.write_u8(b'\n').await.unwrap();
-                }
-            }
+
+        if !is_boguscoin(&self.boguscoin) {
+            anyhow::bail!("boguscoin `{}` is not a boguscoin address", self.boguscoin);
         }
-    });
 
-    tokio::select! {
-        _ = chat_to_proxy => {},
-        _ = proxy_to_chat => {},
+        Ok(())
     }
+}
 
-    Ok(())
+/// Problem 5: proxy to a budget chat server that steals boguscoins.
+pub struct MobInTheMiddle {
+    config: Config,
 }
 
-fn replace_message(msg: String, boguscoin: &str) -> String {
-    let parts: Vec<String> = msg
-        .split(' ')
-        .map(|s| {
-            if s.starts_with('7')
-                && 26 <= s.len()
-                && s.len() <= 35
-                && s.chars().all(char::is_alphanumeric)
-            {
-                boguscoin
-            } else {
-                s
-            }
-            .to_string()
+impl MobInTheMiddle {
+    pub fn new(chat_address: &str, boguscoin: &str) -> Self {
+        Self::with_config(Config {
+            chat_address: chat_address.to_string(),
+            boguscoin: boguscoin.to_string(),
         })
-        .collect();
+    }
 
-    parts.join(" ")
+    pub fn with_config(config: Config) -> Self {
+        Self { config }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+impl crate::service::Service for MobInTheMiddle {
+    fn name(&self) -> &'static str {
+        "mob_in_the_middle"
+    }
 
-    #[tokio::test]
-    async fn check() {
-        let subscriber = tracing_subscriber::FmtSubscriber::builder()
-            .with_max_level(tracing::Level::INFO)
-            .finish();
+    fn refusal(&self, reason: crate::limits::Refusal) -> Option<Vec<u8>> {
+        Some(format!("* Connection refused: {reason}\n").into_bytes())
+    }
 
-        tracing::subscriber::set_global_default(subscriber)
-            .expect("setting default subscriber failed");
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(
+            async move { handle(stream, &self.config.chat_address, &self.config.boguscoin).await },
+        )
+    }
+}
 
-        let budget_chat_port = {
-            // start the budget chat server
-            let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-                .await
-                .expect("open a listener");
+/// Proxies a client to the chat server at `chat_address`.
+pub async fn handle<S>(stream_proxy: S, chat_address: &str, boguscoin: &str) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    let rewrites = crate::metrics::registry().counter(
+        "mob_in_the_middle_rewrites_total",
+        "Messages with a boguscoin address rewritten.",
+        "mob_in_the_middle",
+    );
+
+    let (proxy_reader, proxy_writer) = tokio::io::split(stream_proxy);
+    let (chat_reader, chat_writer) = tokio::net::TcpStream::connect(chat_address)
+        .await?
+        .into_split();
+
+    // the first direction to end closes the other one with it
+    tokio::select! {
+        res = relay(chat_reader, proxy_writer, boguscoin, &rewrites, "chat -> proxy") => res,
+        res = relay(proxy_reader, chat_writer, boguscoin, &rewrites, "proxy -> chat") => res,
+    }
+}
 
-            let local_addr = listener.local_addr().expect("local address works");
+/// Copies the lines from `r` to `w`, stealing the boguscoins, until the end
+/// of the stream.
+async fn relay<R, W>(
+    r: R,
+    mut w: W,
+    boguscoin: &str,
+    rewrites: &crate::metrics::Counter,
+    direction: &str,
+) -> anyhow::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut r = tokio::io::BufReader::new(r);
 
-            tokio::spawn(async move {
-                crate::budget_chat::run(listener).await.expect("run works");
-            });
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
 
-            tracing::info!("budget chat server started at {}", local_addr);
+        line.pop();
+        let resp = replace_message(line.clone(), boguscoin);
+        if resp != line {
+            rewrites.inc();
+        }
 
-            local_addr.port()
-        };
+        tracing::info!("{}: {}", direction, resp);
+        w.write_all(resp.as_bytes()).await?;
+        w.write_u8(b'\n').await?;
+    }
+}
 
-        // start the mob in the middle server (proxy)
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
+/// Boguscoin addresses start with a 7 and are made of 26 to 35 alphanumeric
+/// characters.
+pub fn is_boguscoin(s: &str) -> bool {
+    s.starts_with('7') && 26 <= s.len() && s.len() <= 35 && s.chars().all(char::is_alphanumeric)
+}
 
-        let addr = listener.local_addr().expect("local address works");
-        let port = addr.port();
+/// Replaces every boguscoin address in the message with `boguscoin`.
+pub fn replace_message(msg: String, boguscoin: &str) -> String {
+    let parts: Vec<String> = msg
+        .split(' ')
+        .map(|s| if is_boguscoin(s) { boguscoin } else { s }.to_string())
+        .collect();
 
-        tokio::spawn(async move {
-            run(
-                listener,
-                &format!("127.0.0.1:{budget_chat_port}"),
-                "7YWHMfk9JZe0LM0g1ZauHuiSxhI",
-            )
-            .await
-            .expect("run works");
-        });
+    parts.join(" ")
+}
 
-        tracing::info!("proxy started at {}", addr);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{LineClient, TestServer};
 
-        // alice connect to chat
-        let mut s_alice = tokio::net::TcpStream::connect(format!("127.0.0.1:{budget_chat_port}"))
-            .await
-            .expect("connection with local works");
-        let (r_alice, mut w_alice) = s_alice.split();
-        let mut buf_r_alice = tokio::io::BufReader::new(r_alice).split(b'\n');
-        w_alice.write_all(b"alice\n").await.expect("to write name");
+    const WELCOME: &str = "Welcome to budgetchat! What shall I call you?";
 
-        let welcome = buf_r_alice.next_segment().await.unwrap().unwrap();
-        assert_eq!(welcome, b"Welcome to budgetchat! What shall I call you?");
+    #[tokio::test]
+    async fn check() {
+        let chat = TestServer::start(crate::budget_chat::BudgetChat::new()).await;
+        let proxy = TestServer::start(MobInTheMiddle::new(
+            &chat.addr.to_string(),
+            "7YWHMfk9JZe0LM0g1ZauHuiSxhI",
+        ))
+        .await;
 
-        let contains = buf_r_alice.next_segment().await.unwrap().unwrap();
-        assert_eq!(contains, b"* The room contains: ");
+        // alice connect to chat
+        let mut alice = LineClient::connect(chat.addr).await;
+        alice.send("alice").await;
+        assert_eq!(alice.recv().await.unwrap(), WELCOME);
+        assert_eq!(alice.recv().await.unwrap(), "* The room contains: ");
 
         // federico connect to proxy
-        let mut s_federico = tokio::net::TcpStream::connect(format!("127.0.0.1:{port}"))
-            .await
-            .expect("connection with local works");
-        let (r_federico, mut w_federico) = s_federico.split();
-        let mut buf_r_federico = tokio::io::BufReader::new(r_federico).split(b'\n');
+        let mut federico = LineClient::connect(proxy.addr).await;
+        assert_eq!(federico.recv().await.unwrap(), WELCOME);
 
-        let welcome_msg = buf_r_federico.next_segment().await.unwrap().unwrap();
+        federico.send("federico").await;
+        assert_eq!(federico.recv().await.unwrap(), "* The room contains: alice");
         assert_eq!(
-            welcome_msg,
-            b"Welcome to budgetchat! What shall I call you?"
+            alice.recv().await.unwrap(),
+            "* federico has entered the room"
         );
 
-        w_federico
-            .write_all(b"federico\n")
-            .await
-            .expect("to write name");
-
-        let result = buf_r_federico.next_segment().await.unwrap().unwrap();
-        assert_eq!(result, b"* The room contains: alice");
-
-        let entered = buf_r_alice.next_segment().await.unwrap().unwrap();
-        assert_eq!(entered, b"* federico has entered the room");
-
-        w_federico
-            .write_all(b"Hi alice, please pay 100$ to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX\n")
-            .await
-            .unwrap();
-
-        let result = buf_r_alice.next_segment().await.unwrap().unwrap();
-
+        federico
+            .send("Hi alice, please pay 100$ to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX")
+            .await;
         assert_eq!(
-            result,
-            b"[federico] Hi alice, please pay 100$ to 7YWHMfk9JZe0LM0g1ZauHuiSxhI"
+            alice.recv().await.unwrap(),
+            "[federico] Hi alice, please pay 100$ to 7YWHMfk9JZe0LM0g1ZauHuiSxhI"
         );
     }
+
+    #[tokio::test]
+    async fn close_with_the_chat() {
+        let chat = TestServer::start(crate::budget_chat::BudgetChat::new()).await;
+        let proxy = TestServer::start(MobInTheMiddle::new(
+            &chat.addr.to_string(),
+            "7YWHMfk9JZe0LM0g1ZauHuiSxhI",
+        ))
+        .await;
+
+        // the chat closes the connection on an invalid name, so does the proxy
+        let mut client = LineClient::connect(proxy.addr).await;
+        assert_eq!(client.recv().await.unwrap(), WELCOME);
+        client.send("").await;
+
+        assert_eq!(client.recv().await.unwrap(), "error: name is empty");
+        assert_eq!(client.recv().await, None);
+    }
 }
@@ -0,0 +1,790 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PROTOCOL: &str = "pestcontrol";
+const VERSION: u32 = 1;
+
+/// Messages longer than this are rejected.
+const MAX_MESSAGE: u32 = 1_000_000;
+
+/// Action of a policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Cull,
+    Conserve,
+}
+
+impl Action {
+    fn code(self) -> u8 {
+        match self {
+            Action::Cull => 0x90,
+            Action::Conserve => 0xa0,
+        }
+    }
+
+    fn from_code(code: u8) -> anyhow::Result<Self> {
+        match code {
+            0x90 => Ok(Action::Cull),
+            0xa0 => Ok(Action::Conserve),
+            _ => anyhow::bail!("unknown action {:#04x}", code),
+        }
+    }
+}
+
+/// Range of population wanted for a species.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub species: String,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Messages exchanged with both clients and the authority server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Hello {
+        protocol: String,
+        version: u32,
+    },
+    Error {
+        message: String,
+    },
+    Ok,
+    DialAuthority {
+        site: u32,
+    },
+    TargetPopulations {
+        site: u32,
+        populations: Vec<Target>,
+    },
+    CreatePolicy {
+        species: String,
+        action: Action,
+    },
+    DeletePolicy {
+        policy: u32,
+    },
+    PolicyResult {
+        policy: u32,
+    },
+    SiteVisit {
+        site: u32,
+        populations: Vec<(String, u32)>,
+    },
+}
+
+impl Message {
+    /// The hello message this server speaks.
+    pub fn hello() -> Self {
+        Message::Hello {
+            protocol: PROTOCOL.to_string(),
+            version: VERSION,
+        }
+    }
+
+    /// Encodes the message with its length and checksum.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut content = vec![];
+
+        let kind = match self {
+            Message::Hello { protocol, version } => {
+                put_str(&mut content, protocol);
+                content.extend_from_slice(&version.to_be_bytes());
+                0x50
+            }
+            Message::Error { message } => {
+                put_str(&mut content, message);
+                0x51
+            }
+            Message::Ok => 0x52,
+            Message::DialAuthority { site } => {
+                content.extend_from_slice(&site.to_be_bytes());
+                0x53
+            }
+            Message::TargetPopulations { site, populations } => {
+                content.extend_from_slice(&site.to_be_bytes());
+                content.extend_from_slice(&(populations.len() as u32).to_be_bytes());
+                for t in populations {
+                    put_str(&mut content, &t.species);
+                    content.extend_from_slice(&t.min.to_be_bytes());
+                    content.extend_from_slice(&t.max.to_be_bytes());
+                }
+                0x54
+            }
+            Message::CreatePolicy { species, action } => {
+                put_str(&mut content, species);
+                content.push(action.code());
+                0x55
+            }
+            Message::DeletePolicy { policy } => {
+                content.extend_from_slice(&policy.to_be_bytes());
+                0x56
+            }
+            Message::PolicyResult { policy } => {
+                content.extend_from_slice(&policy.to_be_bytes());
+                0x57
+            }
+            Message::SiteVisit { site, populations } => {
+                content.extend_from_slice(&site.to_be_bytes());
+                content.extend_from_slice(&(populations.len() as u32).to_be_bytes());
+                for (species, count) in populations {
+                    put_str(&mut content, species);
+                    content.extend_from_slice(&count.to_be_bytes());
+                }
+                0x58
+            }
+        };
+
+        // type, length and checksum are part of the length
+        let length = content.len() as u32 + 6;
+
+        let mut buffer = vec![kind];
+        buffer.extend_from_slice(&length.to_be_bytes());
+        buffer.extend(content);
+
+        let sum = buffer.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        buffer.push(0u8.wrapping_sub(sum));
+
+        buffer
+    }
+
+    fn decode(kind: u8, content: &[u8]) -> anyhow::Result<Self> {
+        let mut c = Content { bytes: content };
+
+        let msg = match kind {
+            0x50 => Message::Hello {
+                protocol: c.str()?,
+                version: c.u32()?,
+            },
+            0x51 => Message::Error { message: c.str()? },
+            0x52 => Message::Ok,
+            0x53 => Message::DialAuthority { site: c.u32()? },
+            0x54 => {
+                let site = c.u32()?;
+                let n = c.u32()?;
+                let mut populations = vec![];
+                for _ in 0..n {
+                    populations.push(Target {
+                        species: c.str()?,
+                        min: c.u32()?,
+                        max: c.u32()?,
+                    });
+                }
+
+                Message::TargetPopulations { site, populations }
+            }
+            0x55 => Message::CreatePolicy {
+                species: c.str()?,
+                action: Action::from_code(c.u8()?)?,
+            },
+            0x56 => Message::DeletePolicy { policy: c.u32()? },
+            0x57 => Message::PolicyResult { policy: c.u32()? },
+            0x58 => {
+                let site = c.u32()?;
+                let n = c.u32()?;
+                let mut populations = vec![];
+                for _ in 0..n {
+                    populations.push((c.str()?, c.u32()?));
+                }
+
+                Message::SiteVisit { site, populations }
+            }
+            _ => anyhow::bail!("unknown message type {:#04x}", kind),
+        };
+
+        if !c.bytes.is_empty() {
+            anyhow::bail!("unused bytes in message");
+        }
+
+        Ok(msg)
+    }
+}
+
+fn put_str(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+/// Content of a message still to be parsed.
+struct Content<'a> {
+    bytes: &'a [u8],
+}
+
+impl Content<'_> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        if self.bytes.len() < n {
+            anyhow::bail!("message content too short");
+        }
+
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn str(&mut self) -> anyhow::Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
+
+/// Reads the next message, `None` means that the peer closed the connection.
+pub async fn read_message<R: tokio::io::AsyncRead + Unpin>(
+    r: &mut R,
+) -> anyhow::Result<Option<Message>> {
+    let kind = match r.read_u8().await {
+        Ok(kind) => kind,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let length = r.read_u32().await?;
+    if !(6..=MAX_MESSAGE).contains(&length) {
+        anyhow::bail!("invalid message length {}", length);
+    }
+
+    let mut rest = vec![0; length as usize - 5];
+    r.read_exact(&mut rest).await?;
+
+    let sum = length
+        .to_be_bytes()
+        .iter()
+        .chain(&rest)
+        .fold(kind, |acc, &b| acc.wrapping_add(b));
+    if sum != 0 {
+        anyhow::bail!("invalid checksum");
+    }
+
+    rest.pop();
+    Message::decode(kind, &rest).map(Some)
+}
+
+/// Writes a message.
+pub async fn write_message<W: tokio::io::AsyncWrite + Unpin>(
+    w: &mut W,
+    msg: &Message,
+) -> anyhow::Result<()> {
+    w.write_all(&msg.encode()).await?;
+    Ok(())
+}
+
+/// Reads a message that must be there.
+async fn expect_message<R: tokio::io::AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Message> {
+    match read_message(r).await? {
+        Some(Message::Error { message }) => anyhow::bail!("peer error: {}", message),
+        Some(msg) => Ok(msg),
+        None => anyhow::bail!("connection closed"),
+    }
+}
+
+fn check_hello(msg: &Message) -> anyhow::Result<()> {
+    match msg {
+        Message::Hello { protocol, version } if protocol == PROTOCOL && *version == VERSION => {
+            Ok(())
+        }
+        Message::Hello { .. } => anyhow::bail!("unsupported protocol"),
+        msg => anyhow::bail!("expected hello, found {:?}", msg),
+    }
+}
+
+type Visits = tokio::sync::mpsc::UnboundedSender<Vec<(String, u32)>>;
+
+/// Settings of the pest control server.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address of the authority server.
+    pub authority_address: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            authority_address: "pestcontrol.protohackers.com:20547".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.authority_address.is_empty() {
+            anyhow::bail!("authority_address is empty");
+        }
+
+        Ok(())
+    }
+}
+
+/// Problem 11: reports site visits to the authority server of each site.
+pub struct PestControl {
+    authority_address: String,
+
+    /// Connection to the authority of every site, each one served by its own
+    /// task.
+    sites: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<u32, Visits>>>,
+}
+
+impl PestControl {
+    pub fn new(authority_address: &str) -> Self {
+        Self::with_config(Config {
+            authority_address: authority_address.to_string(),
+        })
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            authority_address: config.authority_address,
+            sites: std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new())),
+        }
+    }
+
+    /// Forwards the observed populations to the task of the site, starting it
+    /// if needed.
+    fn visit(&self, site: u32, populations: Vec<(String, u32)>) {
+        let mut sites = self.sites.lock().unwrap();
+
+        let populations = match sites.get(&site) {
+            Some(tx) => match tx.send(populations) {
+                Ok(_) => return,
+                Err(e) => e.0,
+            },
+            None => populations,
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(populations).expect("receiver is alive");
+        sites.insert(site, tx);
+
+        let authority_address = self.authority_address.clone();
+        let all_sites = self.sites.clone();
+        tokio::spawn(async move {
+            if let Err(e) = authority(site, &authority_address, rx).await {
+                tracing::error!("authority for site {}: {}", site, e);
+            }
+
+            // the next visit opens a new connection, unless it has already
+            let mut sites = all_sites.lock().unwrap();
+            if sites.get(&site).is_some_and(|tx| tx.is_closed()) {
+                sites.remove(&site);
+            }
+        });
+    }
+}
+
+impl crate::service::Service for PestControl {
+    fn name(&self) -> &'static str {
+        "pest_control"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move { self.handler(stream).await })
+    }
+}
+
+impl PestControl {
+    async fn handler(&self, stream: crate::service::Stream) -> anyhow::Result<()> {
+        let (r, mut w) = tokio::io::split(stream);
+        let mut bf = tokio::io::BufReader::new(r);
+
+        write_message(&mut w, &Message::hello()).await?;
+
+        let result = async {
+            match read_message(&mut bf).await? {
+                Some(msg) => check_hello(&msg)?,
+                None => return Ok(()),
+            }
+
+            while let Some(msg) = read_message(&mut bf).await? {
+                let Message::SiteVisit { site, populations } = msg else {
+                    anyhow::bail!("unexpected message {:?}", msg);
+                };
+
+                let mut counts = std::collections::HashMap::new();
+                for (species, count) in &populations {
+                    if *counts.entry(species.clone()).or_insert(*count) != *count {
+                        anyhow::bail!("conflicting counts for {}", species);
+                    }
+                }
+
+                tracing::info!("site visit {}: {:?}", site, populations);
+                self.visit(site, counts.into_iter().collect());
+            }
+
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = &result {
+            tracing::warn!("client error: {}", e);
+            let msg = Message::Error {
+                message: e.to_string(),
+            };
+            write_message(&mut w, &msg).await?;
+        }
+
+        w.flush().await?;
+        w.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+/// Keeps the policies of a site in line with its visits, talking to the
+/// authority server.
+async fn authority(
+    site: u32,
+    address: &str,
+    mut visits: tokio::sync::mpsc::UnboundedReceiver<Vec<(String, u32)>>,
+) -> anyhow::Result<()> {
+    let (r, mut w) = tokio::net::TcpStream::connect(address).await?.into_split();
+    let mut bf = tokio::io::BufReader::new(r);
+
+    write_message(&mut w, &Message::hello()).await?;
+    check_hello(&expect_message(&mut bf).await?)?;
+
+    write_message(&mut w, &Message::DialAuthority { site }).await?;
+    let targets = match expect_message(&mut bf).await? {
+        Message::TargetPopulations {
+            site: target_site,
+            populations,
+        } if target_site == site => populations,
+        msg => anyhow::bail!("expected target populations, found {:?}", msg),
+    };
+
+    tracing::info!("targets for site {}: {:?}", site, targets);
+
+    // policies created on the authority, by species
+    let mut policies = std::collections::HashMap::<String, (u32, Action)>::new();
+
+    while let Some(populations) = visits.recv().await {
+        let counts: std::collections::HashMap<_, _> = populations.into_iter().collect();
+
+        for target in &targets {
+            let count = counts.get(&target.species).copied().unwrap_or(0);
+            let wanted = if count < target.min {
+                Some(Action::Conserve)
+            } else if count > target.max {
+                Some(Action::Cull)
+            } else {
+                None
+            };
+
+            let current = policies.get(&target.species).copied();
+            if current.map(|(_, action)| action) == wanted {
+                continue;
+            }
+
+            if let Some((policy, _)) = current {
+                write_message(&mut w, &Message::DeletePolicy { policy }).await?;
+                match expect_message(&mut bf).await? {
+                    Message::Ok => (),
+                    msg => anyhow::bail!("expected ok, found {:?}", msg),
+                }
+
+                policies.remove(&target.species);
+                tracing::info!("site {}: deleted policy {}", site, policy);
+            }
+
+            if let Some(action) = wanted {
+                let msg = Message::CreatePolicy {
+                    species: target.species.clone(),
+                    action,
+                };
+                write_message(&mut w, &msg).await?;
+
+                let policy = match expect_message(&mut bf).await? {
+                    Message::PolicyResult { policy } => policy,
+                    msg => anyhow::bail!("expected policy result, found {:?}", msg),
+                };
+
+                policies.insert(target.species.clone(), (policy, action));
+                tracing::info!(
+                    "site {}: {:?} {} with policy {}",
+                    site,
+                    action,
+                    target.species,
+                    policy
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{timeout, BinaryClient, TestServer};
+
+    #[test]
+    fn encode_hello() {
+        assert_eq!(
+            Message::hello().encode(),
+            [
+                0x50, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x0b, 0x70, 0x65, 0x73, 0x74, 0x63,
+                0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0xce
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let msgs = [
+            Message::hello(),
+            Message::Error {
+                message: "bad".to_string(),
+            },
+            Message::Ok,
+            Message::DialAuthority { site: 12345 },
+            Message::TargetPopulations {
+                site: 12345,
+                populations: vec![Target {
+                    species: "dog".to_string(),
+                    min: 1,
+                    max: 3,
+                }],
+            },
+            Message::CreatePolicy {
+                species: "dog".to_string(),
+                action: Action::Conserve,
+            },
+            Message::DeletePolicy { policy: 123 },
+            Message::PolicyResult { policy: 123 },
+            Message::SiteVisit {
+                site: 12345,
+                populations: vec![("dog".to_string(), 1), ("rat".to_string(), 5)],
+            },
+        ];
+
+        for msg in msgs {
+            let encoded = msg.encode();
+            let mut r = encoded.as_slice();
+            assert_eq!(read_message(&mut r).await.unwrap(), Some(msg));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_messages() {
+        // wrong checksum
+        let mut encoded = Message::Ok.encode();
+        *encoded.last_mut().unwrap() += 1;
+        assert!(read_message(&mut encoded.as_slice()).await.is_err());
+
+        // length shorter than the content
+        let mut encoded = Message::DialAuthority { site: 1 }.encode();
+        encoded[4] -= 1;
+        encoded.pop();
+        let sum = encoded.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        encoded.push(0u8.wrapping_sub(sum));
+        assert!(read_message(&mut encoded.as_slice()).await.is_err());
+
+        // unknown type
+        let mut encoded = Message::Ok.encode();
+        encoded[0] = 0x60;
+        *encoded.last_mut().unwrap() = encoded[..6].iter().fold(0u8, |acc, &b| acc.wrapping_sub(b));
+        assert!(read_message(&mut encoded.as_slice()).await.is_err());
+    }
+
+    /// Stand-in authority server, reporting the policy changes it receives.
+    async fn start_authority(
+        targets: Vec<Target>,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::sync::mpsc::UnboundedReceiver<Message>,
+    ) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("open a listener");
+
+        let local_addr = listener.local_addr().expect("local address works");
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.expect("accept works");
+                let (mut r, mut w) = stream.into_split();
+                let tx = tx.clone();
+                let targets = targets.clone();
+
+                tokio::spawn(async move {
+                    write_message(&mut w, &Message::hello()).await.unwrap();
+                    check_hello(&expect_message(&mut r).await.unwrap()).unwrap();
+
+                    let Message::DialAuthority { site } = expect_message(&mut r).await.unwrap()
+                    else {
+                        panic!("expected dial authority");
+                    };
+                    tx.send(Message::DialAuthority { site }).unwrap();
+
+                    let populations = targets.clone();
+                    write_message(&mut w, &Message::TargetPopulations { site, populations })
+                        .await
+                        .unwrap();
+
+                    let mut next_policy = 1;
+                    while let Ok(Some(msg)) = read_message(&mut r).await {
+                        let resp = match msg {
+                            Message::CreatePolicy { .. } => {
+                                next_policy += 1;
+                                Message::PolicyResult {
+                                    policy: next_policy - 1,
+                                }
+                            }
+                            Message::DeletePolicy { .. } => Message::Ok,
+                            msg => panic!("unexpected message {:?}", msg),
+                        };
+
+                        tx.send(msg).unwrap();
+                        write_message(&mut w, &resp).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (local_addr, rx)
+    }
+
+    /// Connects a client to the server, handshake done.
+    async fn connect(server: &TestServer) -> BinaryClient {
+        let mut client = server.binary_client().await;
+
+        let hello = timeout(expect_message(&mut client.stream)).await.unwrap();
+        assert_eq!(hello, Message::hello());
+        client.send(&Message::hello().encode()).await;
+
+        client
+    }
+
+    #[tokio::test]
+    async fn policies() {
+        let (authority_addr, mut authority) = start_authority(vec![
+            Target {
+                species: "dog".to_string(),
+                min: 2,
+                max: 5,
+            },
+            Target {
+                species: "cat".to_string(),
+                min: 0,
+                max: 1,
+            },
+        ])
+        .await;
+
+        let server = TestServer::start(PestControl::new(&authority_addr.to_string())).await;
+        let mut client = connect(&server).await;
+
+        let visit = Message::SiteVisit {
+            site: 7,
+            populations: vec![("dog".to_string(), 10), ("rat".to_string(), 100)],
+        };
+        client.send(&visit.encode()).await;
+
+        assert_eq!(
+            timeout(authority.recv()).await.unwrap(),
+            Message::DialAuthority { site: 7 }
+        );
+        assert_eq!(
+            timeout(authority.recv()).await.unwrap(),
+            Message::CreatePolicy {
+                species: "dog".to_string(),
+                action: Action::Cull
+            }
+        );
+
+        // a second client visiting the same site shares the connection
+        let mut other = connect(&server).await;
+        let visit = Message::SiteVisit {
+            site: 7,
+            populations: vec![("dog".to_string(), 1), ("cat".to_string(), 2)],
+        };
+        other.send(&visit.encode()).await;
+
+        let mut changes = vec![
+            timeout(authority.recv()).await.unwrap(),
+            timeout(authority.recv()).await.unwrap(),
+            timeout(authority.recv()).await.unwrap(),
+        ];
+        changes.sort_by_key(|m| format!("{:?}", m));
+        assert_eq!(
+            changes,
+            vec![
+                Message::CreatePolicy {
+                    species: "cat".to_string(),
+                    action: Action::Cull
+                },
+                Message::CreatePolicy {
+                    species: "dog".to_string(),
+                    action: Action::Conserve
+                },
+                Message::DeletePolicy { policy: 1 },
+            ]
+        );
+
+        // the same counts do not change anything
+        other.send(&visit.encode()).await;
+        let none =
+            tokio::time::timeout(std::time::Duration::from_millis(200), authority.recv()).await;
+        assert!(none.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_errors() {
+        let (authority_addr, _authority) = start_authority(vec![]).await;
+        let server = TestServer::start(PestControl::new(&authority_addr.to_string())).await;
+
+        // wrong protocol
+        let mut stream = server.binary_client().await;
+        timeout(expect_message(&mut stream.stream)).await.unwrap();
+        let hello = Message::Hello {
+            protocol: "other".to_string(),
+            version: 1,
+        };
+        stream.send(&hello.encode()).await;
+        assert!(matches!(
+            timeout(read_message(&mut stream.stream)).await,
+            Ok(Some(Message::Error { .. }))
+        ));
+        assert!(matches!(
+            timeout(read_message(&mut stream.stream)).await,
+            Ok(None)
+        ));
+
+        // conflicting counts
+        let mut stream = connect(&server).await;
+        let visit = Message::SiteVisit {
+            site: 1,
+            populations: vec![("dog".to_string(), 1), ("dog".to_string(), 2)],
+        };
+        stream.send(&visit.encode()).await;
+        assert!(matches!(
+            timeout(read_message(&mut stream.stream)).await,
+            Ok(Some(Message::Error { .. }))
+        ));
+
+        // bad checksum
+        let mut stream = connect(&server).await;
+        let mut encoded = Message::SiteVisit {
+            site: 1,
+            populations: vec![],
+        }
+        .encode();
+        *encoded.last_mut().unwrap() += 1;
+        stream.send(&encoded).await;
+        assert!(matches!(
+            timeout(read_message(&mut stream.stream)).await,
+            Ok(Some(Message::Error { .. }))
+        ));
+    }
+}
@@ -1,38 +1,370 @@
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
+/// Method of a line sent by the client, the rest of the line depends on it.
 #[derive(serde::Deserialize, Debug)]
-struct IsPrimeRequest {
-    method: String,
-    number: serde_json::Number,
+pub struct MethodRequest {
+    pub method: String,
+}
+
+/// Whether `number` is prime.
+#[derive(serde::Deserialize, Debug)]
+pub struct IsPrimeRequest {
+    pub method: String,
+    /// Kept as written, numbers can be of any size.
+    pub number: Box<serde_json::value::RawValue>,
+}
+
+#[derive(serde::Serialize)]
+pub struct IsPrimeResponse {
+    pub method: String,
+    pub prime: bool,
+}
+
+/// Prime factors of `number`, a positive `u64`.
+#[derive(serde::Deserialize, Debug)]
+pub struct FactorizeRequest {
+    pub method: String,
+    pub number: Box<serde_json::value::RawValue>,
+}
+
+#[derive(serde::Serialize)]
+pub struct FactorizeResponse {
+    pub method: String,
+    /// In increasing order, repeated with their multiplicity.
+    pub factors: Vec<u64>,
+}
+
+/// Smallest prime above `number`.
+#[derive(serde::Deserialize, Debug)]
+pub struct NextPrimeRequest {
+    pub method: String,
+    pub number: Box<serde_json::value::RawValue>,
+}
+
+#[derive(serde::Serialize)]
+pub struct NextPrimeResponse {
+    pub method: String,
+    pub number: Box<serde_json::value::RawValue>,
+}
+
+/// Largest prime below `number`.
+#[derive(serde::Deserialize, Debug)]
+pub struct PrevPrimeRequest {
+    pub method: String,
+    pub number: Box<serde_json::value::RawValue>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PrevPrimeResponse {
+    pub method: String,
+    /// `null` when there is none, below 3.
+    pub number: Option<Box<serde_json::value::RawValue>>,
+}
+
+/// Number of primes up to `number` included, π(n).
+#[derive(serde::Deserialize, Debug)]
+pub struct PrimeCountRequest {
+    pub method: String,
+    pub number: Box<serde_json::value::RawValue>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PrimeCountResponse {
+    pub method: String,
+    pub count: u64,
+}
+
+/// Primes between `from` and `to` included.
+#[derive(serde::Deserialize, Debug)]
+pub struct PrimesInRangeRequest {
+    pub method: String,
+    pub from: Box<serde_json::value::RawValue>,
+    pub to: Box<serde_json::value::RawValue>,
 }
 
 #[derive(serde::Serialize)]
-struct IsPrimeResponse {
-    method: String,
-    prime: bool,
+pub struct PrimesInRangeResponse {
+    pub method: String,
+    pub primes: Vec<u64>,
+}
+
+/// Answer to a malformed request, the connection is closed right after.
+#[derive(serde::Serialize)]
+pub struct MalformedResponse {
+    pub error: String,
+}
+
+/// Why a request is malformed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Malformed {
+    /// Not a JSON object with a string `method` and a `number`.
+    Json,
+    UnknownMethod,
+    NotANumber,
+    TooManyDigits,
+    /// Not a number the method accepts, e.g. a factorization of `1.5`.
+    OutOfRange,
+}
+
+impl std::fmt::Display for Malformed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Malformed::Json => "malformed request",
+            Malformed::UnknownMethod => "unknown method",
+            Malformed::NotANumber => "number is not a JSON number",
+            Malformed::TooManyDigits => "number has too many digits",
+            Malformed::OutOfRange => "number is out of range",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+/// Settings of the primality service.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Numbers with more digits are rejected as malformed, the test of big
+    /// numbers takes a time cubic in their length: tens of milliseconds for
+    /// 300 digits, most of a second for 1000.
+    pub max_digits: usize,
+
+    /// Numbers tested at the same time by all the connections, on blocking
+    /// threads. Defaults to the number of CPUs.
+    pub workers: usize,
+
+    /// Requests of a connection evaluated or waiting to be answered, the
+    /// connection is not read while there are this many.
+    pub max_in_flight: usize,
+
+    /// Largest bound of `primeCount`, the primes up to it are sieved.
+    pub max_count: u64,
+
+    /// Most numbers in the ranges of `primesInRange`, bounds included.
+    pub max_range: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_digits: 300,
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            max_in_flight: 64,
+            max_count: 10_000_000,
+            max_range: 1_000_000,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_digits == 0 {
+            anyhow::bail!("max_digits must be at least 1");
+        }
+
+        if self.workers == 0 {
+            anyhow::bail!("workers must be at least 1");
+        }
+
+        if self.max_in_flight == 0 {
+            anyhow::bail!("max_in_flight must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+/// Problem 1: tells whether the numbers sent as JSON lines are prime.
+pub struct PrimeTime {
+    config: Config,
+    /// Places on the worker pool, shared by the connections.
+    workers: std::sync::Arc<tokio::sync::Semaphore>,
+}
+
+impl PrimeTime {
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    /// Creates the service, the config has to be valid.
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            workers: std::sync::Arc::new(tokio::sync::Semaphore::new(config.workers)),
+            config,
+        }
+    }
+}
+
+impl Default for PrimeTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Metrics {
+    requests: crate::metrics::Counter,
+    malformed: crate::metrics::Counter,
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: std::sync::OnceLock<Metrics> = std::sync::OnceLock::new();
+    METRICS.get_or_init(|| {
+        let r = crate::metrics::registry();
+        Metrics {
+            requests: r.counter(
+                "prime_time_requests_total",
+                "Requests received, malformed ones included.",
+                "prime_time",
+            ),
+            malformed: r.counter(
+                "prime_time_malformed_requests_total",
+                "Malformed requests received.",
+                "prime_time",
+            ),
+        }
+    })
 }
 
-pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
-    let (r, mut w) = stream.split();
+impl crate::service::Service for PrimeTime {
+    fn name(&self) -> &'static str {
+        "prime_time"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move { handler(stream, &self.config, self.workers.clone()).await })
+    }
+}
+
+/// A request waiting for its response, in the order of the requests.
+enum Pending {
+    Evaluation(Evaluation),
+    Malformed(Malformed),
+}
+
+/// Job running on the worker pool, aborted if the connection is gone before
+/// it gets a worker. A job already running cannot be stopped, it keeps its
+/// worker until it ends.
+struct Evaluation(tokio::task::JoinHandle<anyhow::Result<Vec<u8>>>);
+
+impl Evaluation {
+    fn spawn(job: Job, workers: std::sync::Arc<tokio::sync::Semaphore>) -> Self {
+        Self(tokio::spawn(async move {
+            let worker = workers.acquire_owned().await.expect("pool is never closed");
+            let job = move || {
+                let _worker = worker;
+                job()
+            };
+
+            Ok(tokio::task::spawn_blocking(job).await??)
+        }))
+    }
+
+    async fn response(&mut self) -> anyhow::Result<Vec<u8>> {
+        (&mut self.0).await?
+    }
+}
+
+impl Drop for Evaluation {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Serves a single client until it closes the connection or sends a
+/// malformed request. Requests are evaluated concurrently on the `workers`
+/// while the responses are sent in the order of the requests.
+pub async fn handler<S>(
+    stream: S,
+    config: &Config,
+    workers: std::sync::Arc<tokio::sync::Semaphore>,
+) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (r, w) = tokio::io::split(stream);
+    let (tx, rx) = tokio::sync::mpsc::channel(config.max_in_flight);
+
+    tokio::try_join!(
+        read_requests(r, tx, config, workers),
+        write_responses(w, rx)
+    )?;
+
+    Ok(())
+}
+
+/// Reads the requests and starts their evaluation, until the end of the
+/// stream or the first malformed request.
+async fn read_requests<R>(
+    r: R,
+    tx: tokio::sync::mpsc::Sender<Pending>,
+    config: &Config,
+    workers: std::sync::Arc<tokio::sync::Semaphore>,
+) -> anyhow::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let metrics = metrics();
     let mut bf = tokio::io::BufReader::new(r);
     let mut buffer = vec![];
 
     loop {
         buffer.clear();
-        match bf.read_until(b'\n', &mut buffer).await? {
-            0 => break,
-            bytes_read => match validate(&buffer[..bytes_read]) {
-                Ok(v) => {
-                    let resp = IsPrimeResponse {
-                        method: "isPrime".to_string(),
-                        prime: v,
-                    };
-                    let body = serde_json::to_vec(&resp)?;
-                    w.write_all(&body).await?;
-                    w.write_u8(b'\n').await?;
-                }
-                Err(e) => w.write_all(e.as_bytes()).await?,
-            },
+        let bytes_read = bf.read_until(b'\n', &mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+
+        metrics.requests.inc();
+        let pending = match evaluate(&buffer[..bytes_read], config) {
+            Ok(job) => Pending::Evaluation(Evaluation::spawn(job, workers.clone())),
+            Err(e) => Pending::Malformed(e),
+        };
+        let malformed = matches!(pending, Pending::Malformed(_));
+
+        // waits while too many requests are in flight, the writer is gone
+        // if the client stopped reading
+        if tx.send(pending).await.is_err() || malformed {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends the responses in the order of the requests, the output is flushed
+/// when there is nothing ready to send.
+async fn write_responses<W>(
+    w: W,
+    mut rx: tokio::sync::mpsc::Receiver<Pending>,
+) -> anyhow::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let metrics = metrics();
+    let mut w = tokio::io::BufWriter::new(w);
+
+    while let Some(pending) = rx.recv().await {
+        let body = match pending {
+            Pending::Evaluation(mut evaluation) => evaluation.response().await?,
+            Pending::Malformed(e) => {
+                tracing::debug!("malformed request: {}", e);
+                metrics.malformed.inc();
+
+                let body = serde_json::to_vec(&MalformedResponse {
+                    error: e.to_string(),
+                })?;
+                w.write_all(&body).await?;
+                w.write_u8(b'\n').await?;
+                break;
+            }
+        };
+
+        w.write_all(&body).await?;
+        w.write_u8(b'\n').await?;
+        if rx.is_empty() {
+            w.flush().await?;
         }
     }
 
@@ -42,188 +374,1019 @@ pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn validate(buffer: &[u8]) -> Result<bool, &'static str> {
-    let req = serde_json::from_slice::<IsPrimeRequest>(buffer).map_err(|_| "error")?;
+/// Work of a request run on the worker pool, giving the response.
+pub type Job = Box<dyn FnOnce() -> serde_json::Result<Vec<u8>> + Send>;
+
+/// Parses the request of a method, returning the job answering it.
+type Method = fn(&[u8], &Config) -> Result<Job, Malformed>;
 
+/// Every method of the protocol.
+const METHODS: &[(&str, Method)] = &[
+    ("isPrime", is_prime_method),
+    ("factorize", factorize_method),
+    ("nextPrime", next_prime_method),
+    ("prevPrime", prev_prime_method),
+    ("primeCount", prime_count_method),
+    ("primesInRange", primes_in_range_method),
+];
+
+/// Parses an `isPrime` request line, returning whether its number is prime or
+/// why it is malformed. Extraneous fields are ignored, the other methods are
+/// answered by [`evaluate`].
+pub fn validate(buffer: &[u8], config: &Config) -> Result<bool, Malformed> {
+    let req = serde_json::from_slice::<MethodRequest>(buffer).map_err(|_| Malformed::Json)?;
     if req.method != "isPrime" {
-        return Err("error");
+        return Err(Malformed::UnknownMethod);
     }
 
-    match req.number.as_u64() {
-        Some(n) => Ok(is_prime(n)),
+    let req: IsPrimeRequest = request(buffer)?;
+    match parse_integer(req.number.get(), config.max_digits)? {
+        Some(n) => Ok(is_prime_big(&n)),
         None => Ok(false),
     }
 }
 
-fn is_prime(n: u64) -> bool {
+/// Parses a request line of any method, returning the job giving its
+/// response or why it is malformed. Extraneous fields are ignored.
+pub fn evaluate(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req = serde_json::from_slice::<MethodRequest>(buffer).map_err(|_| Malformed::Json)?;
+
+    let (_, method) = METHODS
+        .iter()
+        .find(|(name, _)| *name == req.method)
+        .ok_or(Malformed::UnknownMethod)?;
+
+    method(buffer, config)
+}
+
+fn request<'a, T: serde::Deserialize<'a>>(buffer: &'a [u8]) -> Result<T, Malformed> {
+    serde_json::from_slice(buffer).map_err(|_| Malformed::Json)
+}
+
+/// A non-negative integer.
+fn natural(
+    raw: &serde_json::value::RawValue,
+    config: &Config,
+) -> Result<num_bigint::BigUint, Malformed> {
+    parse_integer(raw.get(), config.max_digits)?.ok_or(Malformed::OutOfRange)
+}
+
+/// A non-negative integer fitting in a `u64`.
+fn natural_u64(raw: &serde_json::value::RawValue, config: &Config) -> Result<u64, Malformed> {
+    u64::try_from(natural(raw, config)?).map_err(|_| Malformed::OutOfRange)
+}
+
+/// A big integer written as a JSON number.
+fn raw_number(n: &num_bigint::BigUint) -> Box<serde_json::value::RawValue> {
+    serde_json::value::RawValue::from_string(n.to_string()).expect("integers are JSON numbers")
+}
+
+fn is_prime_method(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req: IsPrimeRequest = request(buffer)?;
+    let n = parse_integer(req.number.get(), config.max_digits)?;
+
+    Ok(Box::new(move || {
+        serde_json::to_vec(&IsPrimeResponse {
+            method: req.method,
+            prime: n.is_some_and(|n| is_prime_big(&n)),
+        })
+    }))
+}
+
+fn factorize_method(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req: FactorizeRequest = request(buffer)?;
+    let n = natural_u64(&req.number, config)?;
+    if n == 0 {
+        return Err(Malformed::OutOfRange);
+    }
+
+    Ok(Box::new(move || {
+        serde_json::to_vec(&FactorizeResponse {
+            method: req.method,
+            factors: factorize(n),
+        })
+    }))
+}
+
+fn next_prime_method(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req: NextPrimeRequest = request(buffer)?;
+    let n = natural(&req.number, config)?;
+
+    Ok(Box::new(move || {
+        serde_json::to_vec(&NextPrimeResponse {
+            method: req.method,
+            number: raw_number(&next_prime(&n)),
+        })
+    }))
+}
+
+fn prev_prime_method(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req: PrevPrimeRequest = request(buffer)?;
+    let n = natural(&req.number, config)?;
+
+    Ok(Box::new(move || {
+        serde_json::to_vec(&PrevPrimeResponse {
+            method: req.method,
+            number: prev_prime(&n).as_ref().map(raw_number),
+        })
+    }))
+}
+
+fn prime_count_method(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req: PrimeCountRequest = request(buffer)?;
+    let n = natural_u64(&req.number, config)?;
+    if n > config.max_count {
+        return Err(Malformed::OutOfRange);
+    }
+
+    Ok(Box::new(move || {
+        serde_json::to_vec(&PrimeCountResponse {
+            method: req.method,
+            count: prime_count(n),
+        })
+    }))
+}
+
+fn primes_in_range_method(buffer: &[u8], config: &Config) -> Result<Job, Malformed> {
+    let req: PrimesInRangeRequest = request(buffer)?;
+    let from = natural_u64(&req.from, config)?;
+    let to = natural_u64(&req.to, config)?;
+    if to.saturating_sub(from) >= config.max_range {
+        return Err(Malformed::OutOfRange);
+    }
+
+    Ok(Box::new(move || {
+        serde_json::to_vec(&PrimesInRangeResponse {
+            method: req.method,
+            primes: (from..=to).filter(|&n| is_prime(n)).collect(),
+        })
+    }))
+}
+
+/// Parses a JSON number of any size, `None` if it is negative or not an
+/// integer, e.g. `1.5`, while `1.5e3` and `15e2` are both `1500`. Numbers
+/// with more than `max_digits` digits, and values that are not numbers, are
+/// rejected.
+pub fn parse_integer(
+    raw: &str,
+    max_digits: usize,
+) -> Result<Option<num_bigint::BigUint>, Malformed> {
+    // the value is valid JSON, only numbers start with these
+    if !raw.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+        return Err(Malformed::NotANumber);
+    }
+
+    let (negative, raw) = match raw.strip_prefix('-') {
+        Some(raw) => (true, raw),
+        None => (false, raw),
+    };
+
+    let (mantissa, exponent) = match raw.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, parse_exponent(exponent)),
+        None => (raw, 0),
+    };
+
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{integer}{fraction}");
+
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(Some(num_bigint::BigUint::ZERO));
+    }
+
+    if negative {
+        return Ok(None);
+    }
+
+    // value = significant * 10^exponent
+    let significant = digits.trim_end_matches('0');
+    let exponent = exponent
+        .saturating_sub(fraction.len() as i64)
+        .saturating_add((digits.len() - significant.len()) as i64);
+
+    if exponent < 0 {
+        return Ok(None);
+    }
+
+    if exponent.saturating_add(significant.len() as i64) > max_digits as i64 {
+        return Err(Malformed::TooManyDigits);
+    }
+
+    let significant: num_bigint::BigUint =
+        significant.parse().map_err(|_| Malformed::NotANumber)?;
+    Ok(Some(
+        significant * num_bigint::BigUint::from(10u32).pow(exponent as u32),
+    ))
+}
+
+/// Exponent of a JSON number, saturated as the value is too large anyway.
+fn parse_exponent(s: &str) -> i64 {
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+
+    let value = digits.bytes().fold(0i64, |acc, d| {
+        acc.saturating_mul(10).saturating_add((d - b'0') as i64)
+    });
+
+    if negative {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Miller-Rabin rounds for numbers above `u64::MAX`, a composite passes
+/// each round with a probability of at most 1/4.
+const BIG_ROUNDS: usize = 32;
+
+/// Tests numbers of any size: exactly for the ones fitting in a `u64`,
+/// with random bases for the others.
+pub fn is_prime_big(n: &num_bigint::BigUint) -> bool {
+    use num_bigint::RandBigInt;
+
+    if let Ok(n) = u64::try_from(n) {
+        return is_prime(n);
+    }
+
+    if SMALL_PRIMES
+        .iter()
+        .any(|&p| n % p == num_bigint::BigUint::ZERO)
+    {
+        return false;
+    }
+
+    let one = num_bigint::BigUint::from(1u32);
+    let two = num_bigint::BigUint::from(2u32);
+    let n_minus_one = n - &one;
+
+    // n - 1 = d * 2^s with d odd
+    let s = n_minus_one.trailing_zeros().expect("n is above 1");
+    let d = &n_minus_one >> s;
+
+    let mut rng = rand::thread_rng();
+    (0..BIG_ROUNDS).all(|_| {
+        let a = rng.gen_biguint_range(&two, &n_minus_one);
+        let mut x = a.modpow(&d, n);
+        if x == one || x == n_minus_one {
+            return true;
+        }
+
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                return true;
+            }
+        }
+
+        false
+    })
+}
+
+/// Primes used to rule out most composites before running Miller-Rabin.
+const SMALL_PRIMES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Deterministic Miller-Rabin test: the first twelve primes as bases are
+/// enough for every `u64`.
+pub fn is_prime(n: u64) -> bool {
     if n < 2 {
         return false;
     }
 
-    for i in 2..n {
-        if n % i == 0 {
+    for p in SMALL_PRIMES {
+        if n == p {
+            return true;
+        }
+        if n.is_multiple_of(p) {
             return false;
         }
     }
 
-    true
+    // n - 1 = d * 2^s with d odd
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+
+    SMALL_PRIMES.iter().all(|&a| {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            return true;
+        }
+
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                return true;
+            }
+        }
+
+        false
+    })
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (a as u128 * b as u128 % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+
+    result
+}
+
+/// Prime factors of `n` in increasing order, repeated with their
+/// multiplicity, none for 1.
+pub fn factorize(mut n: u64) -> Vec<u64> {
+    let mut factors = vec![];
+
+    for p in SMALL_PRIMES {
+        while n.is_multiple_of(p) {
+            factors.push(p);
+            n /= p;
+        }
+    }
+
+    let mut composites = vec![n];
+    while let Some(n) = composites.pop() {
+        if n == 1 {
+            continue;
+        }
+
+        if is_prime(n) {
+            factors.push(n);
+            continue;
+        }
+
+        let d = pollard_rho(n);
+        composites.push(d);
+        composites.push(n / d);
+    }
+
+    factors.sort_unstable();
+    factors
+}
+
+/// A non-trivial divisor of the odd composite `n`.
+fn pollard_rho(n: u64) -> u64 {
+    for c in 1.. {
+        let f = |x: u64| ((mul_mod(x, x, n) as u128 + c) % n as u128) as u64;
+
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = f(x);
+            y = f(f(y));
+            d = gcd(x.abs_diff(y), n);
+        }
+
+        // the cycle closed without a divisor, retry with another polynomial
+        if d != n {
+            return d;
+        }
+    }
+
+    unreachable!("a composite has a divisor")
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+
+    a
+}
+
+/// Smallest prime above `n`.
+pub fn next_prime(n: &num_bigint::BigUint) -> num_bigint::BigUint {
+    if *n < num_bigint::BigUint::from(2u32) {
+        return 2u32.into();
+    }
+
+    // odd candidates only
+    let mut candidate = n + 1u32;
+    if !candidate.bit(0) {
+        candidate += 1u32;
+    }
+
+    while !is_prime_big(&candidate) {
+        candidate += 2u32;
+    }
+
+    candidate
+}
+
+/// Largest prime below `n`, none below 3.
+pub fn prev_prime(n: &num_bigint::BigUint) -> Option<num_bigint::BigUint> {
+    if *n <= num_bigint::BigUint::from(3u32) {
+        return (*n == num_bigint::BigUint::from(3u32)).then(|| 2u32.into());
+    }
+
+    // odd candidates only, down to 3 which is prime
+    let mut candidate = n - 1u32;
+    if !candidate.bit(0) {
+        candidate -= 1u32;
+    }
+
+    while !is_prime_big(&candidate) {
+        candidate -= 2u32;
+    }
+
+    Some(candidate)
+}
+
+/// Number of primes up to `n` included, by sieving the odd numbers.
+pub fn prime_count(n: u64) -> u64 {
+    if n < 2 {
+        return 0;
+    }
+
+    // composite[i] is for 2i + 1
+    let len = n.div_ceil(2) as usize;
+    let mut composite = vec![false; len];
+    composite[0] = true;
+
+    let mut i = 1;
+    while (2 * i + 1) * (2 * i + 1) <= n as usize {
+        if !composite[i] {
+            let p = 2 * i + 1;
+            for j in ((p * p) / 2..len).step_by(p) {
+                composite[j] = true;
+            }
+        }
+        i += 1;
+    }
+
+    // 2 is the only even prime
+    1 + composite.iter().filter(|&&c| !c).count() as u64
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    use tokio::io::AsyncReadExt;
+    use crate::test_support::TestServer;
 
     #[test]
     fn check_prime() {
         assert!(is_prime(11));
         assert!(!is_prime(10));
+
+        assert!(!is_prime(0));
+        assert!(!is_prime(1));
+        assert!(is_prime(2));
+        assert!(is_prime(37));
+        assert!(!is_prime(41 * 43));
+
+        // strong pseudoprimes to the first bases
+        assert!(!is_prime(3_215_031_751));
+        assert!(!is_prime(3_825_123_056_546_413_051));
+
+        assert!(is_prime(18_446_744_073_709_551_557)); // largest u64 prime
+        assert!(!is_prime(u64::MAX));
+        assert!(!is_prime(4_294_967_291 * 4_294_967_279));
     }
 
-    #[tokio::test]
-    async fn number_not_prime() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
+    fn integer(raw: &str) -> Result<Option<num_bigint::BigUint>, Malformed> {
+        parse_integer(raw, 20)
+    }
 
-        let local_addr = listener.local_addr().expect("local address works");
+    #[test]
+    fn parse_numbers() {
+        let big = |n: u64| Ok(Some(num_bigint::BigUint::from(n)));
+
+        assert_eq!(integer("0"), big(0));
+        assert_eq!(integer("-0.0"), big(0));
+        assert_eq!(integer("0e999999999999999999999"), big(0));
+        assert_eq!(integer("17"), big(17));
+        assert_eq!(integer("17.0"), big(17));
+        assert_eq!(integer("1.7e1"), big(17));
+        assert_eq!(integer("170E-1"), big(17));
+        assert_eq!(integer("15e2"), big(1500));
+        assert_eq!(integer("1.5e+3"), big(1500));
+        assert_eq!(
+            integer("18446744073709551616")
+                .unwrap()
+                .unwrap()
+                .to_string(),
+            "18446744073709551616"
+        );
 
-        tokio::spawn(async move {
-            loop {
-                let stream = match listener.accept().await {
-                    Ok((stream, address)) => {
-                        println!("connection received for {}", address);
+        // not integers, or negative
+        assert_eq!(integer("1.5"), Ok(None));
+        assert_eq!(integer("17e-1"), Ok(None));
+        assert_eq!(integer("1e-999999999999999999999"), Ok(None));
+        assert_eq!(integer("-17"), Ok(None));
 
-                        stream
-                    }
-                    Err(_) => panic!("error on accepting connection"),
-                };
+        // too many digits
+        assert_eq!(integer("1e20"), Err(Malformed::TooManyDigits));
+        assert_eq!(
+            integer("123456789012345678901"),
+            Err(Malformed::TooManyDigits)
+        );
+        assert_eq!(
+            integer("1e999999999999999999999"),
+            Err(Malformed::TooManyDigits)
+        );
+        assert_eq!(
+            integer("99999999999999999999"),
+            Ok(Some("99999999999999999999".parse().unwrap()))
+        );
 
-                tokio::spawn(handler(stream));
+        // not numbers
+        assert_eq!(integer(r#""17""#), Err(Malformed::NotANumber));
+        assert_eq!(integer("null"), Err(Malformed::NotANumber));
+        assert_eq!(integer("[17]"), Err(Malformed::NotANumber));
+    }
+
+    #[test]
+    fn check_big_prime() {
+        let big = |s: &str| s.parse::<num_bigint::BigUint>().unwrap();
+        let one = num_bigint::BigUint::from(1u32);
+
+        // mersenne primes
+        let m89 = (&one << 89) - &one;
+        let m107 = (&one << 107) - &one;
+        assert!(is_prime_big(&m89));
+        assert!(is_prime_big(&((&one << 127) - &one)));
+        assert!(!is_prime_big(&(&m89 * &m107)));
+
+        // first prime after a googol
+        let googol = num_bigint::BigUint::from(10u32).pow(100);
+        assert!(is_prime_big(&(&googol + 267u32)));
+        assert!(!is_prime_big(&(&googol + 1u32)));
+
+        // fermat number F7 is composite
+        assert!(!is_prime_big(&((&one << 128) + &one)));
+
+        assert!(is_prime_big(&big("18446744073709551557")));
+        assert!(!is_prime_big(&big("18446744073709551615")));
+    }
+
+    const SIEVE_LIMIT: usize = 1_000_000;
+
+    /// Primality of the numbers below `limit`, by the sieve of Eratosthenes.
+    fn sieve(limit: usize) -> Vec<bool> {
+        let mut primes = vec![true; limit];
+        primes[0] = false;
+        primes[1] = false;
+
+        let mut i = 2;
+        while i * i < limit {
+            if primes[i] {
+                for j in (i * i..limit).step_by(i) {
+                    primes[j] = false;
+                }
             }
-        });
+            i += 1;
+        }
 
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
+        primes
+    }
 
-        let (r, mut w) = stream.split();
+    proptest::proptest! {
+        #[test]
+        fn same_as_sieve(n in 0..SIEVE_LIMIT) {
+            static SIEVE: std::sync::OnceLock<Vec<bool>> = std::sync::OnceLock::new();
+            let sieve = SIEVE.get_or_init(|| sieve(SIEVE_LIMIT));
 
-        let payload = br#"{"method":"isPrime","number":123}"#;
-        let written = w.write(payload).await.expect("to write payload");
-        w.write_u8(b'\n').await.unwrap();
+            proptest::prop_assert_eq!(is_prime(n as u64), sieve[n]);
+        }
 
-        w.flush().await.expect("to flush msg");
-        w.shutdown().await.expect("shutdown");
+        #[test]
+        fn factors_are_prime(n in 1..u64::MAX) {
+            let factors = factorize(n);
 
-        assert_ne!(written, 0);
+            proptest::prop_assert_eq!(factors.iter().product::<u64>(), n);
+            proptest::prop_assert!(factors.iter().all(|&p| is_prime(p)));
+            proptest::prop_assert!(factors.is_sorted());
+        }
 
-        let mut buffer = vec![];
-        let mut bf = tokio::io::BufReader::new(r);
-        let n = bf.read_until(b'\n', &mut buffer).await.unwrap();
-        assert!(n > 0);
+        #[test]
+        fn products_are_composite(a in 2..u32::MAX as u64, b in 2..u32::MAX as u64) {
+            proptest::prop_assert!(!is_prime(a * b));
+        }
+    }
 
-        let s = std::str::from_utf8(&buffer).unwrap();
-        assert_eq!(s, "{\"method\":\"isPrime\",\"prime\":false}\n");
+    #[tokio::test]
+    async fn number_not_prime() {
+        let server = TestServer::start(PrimeTime::new()).await;
+        let mut client = server.line_client().await;
+
+        client.send(r#"{"method":"isPrime","number":123}"#).await;
+        client.shutdown().await;
+
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":false}"#
+        );
     }
 
     #[tokio::test]
     async fn number_prime() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
+        let server = TestServer::start(PrimeTime::new()).await;
+        let mut client = server.line_client().await;
 
-        let local_addr = listener.local_addr().expect("local address works");
+        client
+            .send_raw(br#"{"method":"isPrime","number":11}"#)
+            .await;
+        client.shutdown().await;
 
-        tokio::spawn(async move {
-            loop {
-                let stream = match listener.accept().await {
-                    Ok((stream, address)) => {
-                        println!("connection received for {}", address);
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+    }
 
-                        stream
-                    }
-                    Err(_) => panic!("error on accepting connection"),
-                };
+    #[tokio::test]
+    async fn big_numbers() {
+        let server = TestServer::start(PrimeTime::new()).await;
+        let mut client = server.line_client().await;
 
-                tokio::spawn(handler(stream));
-            }
-        });
+        // 2^127 - 1 and 2^128 + 1
+        client
+            .send(r#"{"method":"isPrime","number":170141183460469231731687303715884105727}"#)
+            .await;
+        client
+            .send(r#"{"method":"isPrime","number":340282366920938463463374607431768211457}"#)
+            .await;
+        client.send(r#"{"method":"isPrime","number":1.1e1}"#).await;
+        client.shutdown().await;
 
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":false}"#
+        );
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_digits() {
+        let server = TestServer::start(PrimeTime::with_config(Config {
+            max_digits: 3,
+            ..Config::default()
+        }))
+        .await;
+        let mut client = server.line_client().await;
+
+        client.send(r#"{"method":"isPrime","number":1009}"#).await;
+
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"error":"number has too many digits"}"#
+        );
+        assert_eq!(client.recv().await, None);
+    }
 
-        let (r, mut w) = stream.split();
+    #[test]
+    fn malformed_requests() {
+        let check = answer;
+
+        // not a request object
+        assert_eq!(check(""), Err(Malformed::Json));
+        assert_eq!(check("{"), Err(Malformed::Json));
+        assert_eq!(check("[]"), Err(Malformed::Json));
+        assert_eq!(check(r#""isPrime""#), Err(Malformed::Json));
+        assert_eq!(check(r#"{"number":7}"#), Err(Malformed::Json));
+        assert_eq!(check(r#"{"method":"isPrime"}"#), Err(Malformed::Json));
+        assert_eq!(check(r#"{"method":1,"number":7}"#), Err(Malformed::Json));
+        assert_eq!(check(r#"{"method":null,"number":7}"#), Err(Malformed::Json));
+
+        assert_eq!(
+            check(r#"{"method":"isprime","number":7}"#),
+            Err(Malformed::UnknownMethod)
+        );
 
-        let payload = br#"{"method":"isPrime","number":11}"#;
-        let written = w.write(payload).await.expect("to write payload");
+        // the number is not coerced
+        for number in [r#""7""#, "true", "null", "[7]", r#"{"n":7}"#] {
+            assert_eq!(
+                check(&format!(r#"{{"method":"isPrime","number":{number}}}"#)),
+                Err(Malformed::NotANumber),
+                "{}",
+                number
+            );
+        }
 
-        w.flush().await.expect("to flush msg");
-        w.shutdown().await.expect("shutdown");
+        // well-formed: extraneous fields are ignored, non-integers are not prime
+        let prime = |prime: bool| Ok(format!(r#"{{"method":"isPrime","prime":{prime}}}"#));
+        let validate = |line: &str| validate(line.as_bytes(), &Config::default());
+        assert_eq!(
+            validate(r#"{"method":"isPrime","number":7,"prime":false}"#),
+            Ok(true)
+        );
+        assert_eq!(validate(r#"{"method":"isPrime","number":7.5}"#), Ok(false));
+        assert_eq!(
+            validate(r#"{"method":"nextPrime","number":7}"#),
+            Err(Malformed::UnknownMethod)
+        );
 
-        assert_ne!(written, 0);
+        assert_eq!(
+            check(r#"{"method":"isPrime","number":7,"prime":false}"#),
+            prime(true)
+        );
+        assert_eq!(check(r#"{"method":"isPrime","number":7.5}"#), prime(false));
+        assert_eq!(check(r#"{"method":"isPrime","number":-7}"#), prime(false));
+    }
 
-        let mut buffer = vec![];
-        let mut bf = tokio::io::BufReader::new(r);
-        let n = bf.read_until(b'\n', &mut buffer).await.unwrap();
-        assert!(n > 0);
+    /// Response to a request line, evaluated in place.
+    fn answer(line: &str) -> Result<String, Malformed> {
+        let config = Config {
+            max_count: 1000,
+            max_range: 100,
+            ..Config::default()
+        };
+        let job = evaluate(line.as_bytes(), &config)?;
 
-        let s = std::str::from_utf8(&buffer).unwrap();
-        assert_eq!(s, "{\"method\":\"isPrime\",\"prime\":true}\n");
+        Ok(String::from_utf8(job().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn math_methods() {
+        let ok = |s: &str| Ok(s.to_string());
+
+        assert_eq!(
+            answer(r#"{"method":"factorize","number":360}"#),
+            ok(r#"{"method":"factorize","factors":[2,2,2,3,3,5]}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"factorize","number":1}"#),
+            ok(r#"{"method":"factorize","factors":[]}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"nextPrime","number":13}"#),
+            ok(r#"{"method":"nextPrime","number":17}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"nextPrime","number":18446744073709551557}"#),
+            ok(r#"{"method":"nextPrime","number":18446744073709551629}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"prevPrime","number":13}"#),
+            ok(r#"{"method":"prevPrime","number":11}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"prevPrime","number":2}"#),
+            ok(r#"{"method":"prevPrime","number":null}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"primeCount","number":1000}"#),
+            ok(r#"{"method":"primeCount","count":168}"#)
+        );
+        assert_eq!(
+            answer(r#"{"method":"primesInRange","from":10,"to":30}"#),
+            ok(r#"{"method":"primesInRange","primes":[11,13,17,19,23,29]}"#)
+        );
+        assert!(answer(r#"{"method":"primesInRange","from":1,"to":100}"#).is_ok());
+        assert_eq!(
+            answer(r#"{"method":"primesInRange","from":30,"to":10}"#),
+            ok(r#"{"method":"primesInRange","primes":[]}"#)
+        );
+
+        // numbers the methods do not accept
+        for line in [
+            r#"{"method":"factorize","number":0}"#,
+            r#"{"method":"factorize","number":1.5}"#,
+            r#"{"method":"factorize","number":18446744073709551616}"#,
+            r#"{"method":"nextPrime","number":-1}"#,
+            r#"{"method":"prevPrime","number":7.5}"#,
+            r#"{"method":"primeCount","number":1001}"#,
+            r#"{"method":"primesInRange","from":0,"to":100}"#,
+        ] {
+            assert_eq!(answer(line), Err(Malformed::OutOfRange), "{}", line);
+        }
+
+        assert_eq!(
+            answer(r#"{"method":"primesInRange","from":10}"#),
+            Err(Malformed::Json)
+        );
+        assert_eq!(
+            answer(r#"{"method":"nextPrime","number":"13"}"#),
+            Err(Malformed::NotANumber)
+        );
+    }
+
+    #[test]
+    fn check_factorize() {
+        assert_eq!(factorize(2), vec![2]);
+        assert_eq!(
+            factorize(4_294_967_291 * 4_294_967_279),
+            vec![4_294_967_279, 4_294_967_291]
+        );
+        assert_eq!(
+            factorize(18_446_744_073_709_551_557),
+            vec![18_446_744_073_709_551_557]
+        );
+        assert_eq!(factorize(1_000_003 * 1_000_003), vec![1_000_003, 1_000_003]);
+        assert_eq!(
+            factorize(u64::MAX),
+            vec![3, 5, 17, 257, 641, 65_537, 6_700_417]
+        );
+    }
+
+    #[test]
+    fn check_next_and_prev_prime() {
+        let big = |n: u64| num_bigint::BigUint::from(n);
+
+        assert_eq!(next_prime(&big(0)), big(2));
+        assert_eq!(next_prime(&big(2)), big(3));
+        assert_eq!(next_prime(&big(7)), big(11));
+        assert_eq!(prev_prime(&big(3)), Some(big(2)));
+        assert_eq!(prev_prime(&big(4)), Some(big(3)));
+        assert_eq!(prev_prime(&big(11)), Some(big(7)));
+        assert_eq!(prev_prime(&big(0)), None);
+
+        let googol = num_bigint::BigUint::from(10u32).pow(100);
+        assert_eq!(next_prime(&googol), &googol + 267u32);
+        // no prime between a googol and the next one
+        let below = prev_prime(&(&googol + 267u32)).unwrap();
+        assert!(below < googol && is_prime_big(&below));
+    }
+
+    #[test]
+    fn check_prime_count() {
+        assert_eq!(prime_count(0), 0);
+        assert_eq!(prime_count(2), 1);
+        assert_eq!(prime_count(3), 2);
+        assert_eq!(prime_count(10), 4);
+        assert_eq!(prime_count(1_000_000), 78_498);
+
+        let sieve = sieve(10_000);
+        for n in [1, 4, 9, 25, 97, 100, 9_999] {
+            let expected = sieve[..=n].iter().filter(|&&p| p).count() as u64;
+            assert_eq!(prime_count(n as u64), expected, "{}", n);
+        }
     }
 
     #[tokio::test]
     async fn different_method() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
+        let server = TestServer::start(PrimeTime::new()).await;
+        let mut client = server.line_client().await;
 
-        let local_addr = listener.local_addr().expect("local address works");
+        client.send(r#"{"method":"aaaa","number":11}"#).await;
 
-        tokio::spawn(async move {
-            loop {
-                let stream = match listener.accept().await {
-                    Ok((stream, address)) => {
-                        println!("connection received for {}", address);
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"error":"unknown method"}"#
+        );
+        assert_eq!(client.recv().await, None);
+    }
 
-                        stream
-                    }
-                    Err(_) => panic!("error on accepting connection"),
-                };
+    #[tokio::test]
+    async fn disconnect_after_malformed_request() {
+        let server = TestServer::start(PrimeTime::new()).await;
+        let mut client = server.line_client().await;
 
-                tokio::spawn(handler(stream));
-            }
+        client.send(r#"{"method":"isPrime","number":7}"#).await;
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+
+        client.send(r#"{"method":"isPrime","number":"7"}"#).await;
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"error":"number is not a JSON number"}"#
+        );
+        assert_eq!(client.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_in_order() {
+        let server = TestServer::start(PrimeTime::with_config(Config {
+            workers: 2,
+            max_in_flight: 4,
+            ..Config::default()
+        }))
+        .await;
+        let mut client = server.line_client().await;
+
+        // the slow first prime is answered before the quick ones after it
+        let googol_prime = "1".to_string() + &"0".repeat(97) + "267";
+        let mut numbers = vec![googol_prime];
+        numbers.extend((0..40u64).map(|n| n.to_string()));
+
+        let batch: String = numbers
+            .iter()
+            .map(|n| format!("{{\"method\":\"isPrime\",\"number\":{n}}}\n"))
+            .collect();
+        client.send_raw(batch.as_bytes()).await;
+
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+        for n in 0..40 {
+            assert_eq!(
+                client.recv().await.unwrap(),
+                format!(r#"{{"method":"isPrime","prime":{}}}"#, is_prime(n)),
+                "{}",
+                n
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn stop_reading_when_too_many_in_flight() {
+        use tokio::io::AsyncReadExt;
+
+        let config = Config {
+            workers: 1,
+            max_in_flight: 2,
+            ..Config::default()
+        };
+        let workers = std::sync::Arc::new(tokio::sync::Semaphore::new(config.workers));
+
+        let (client, server) = tokio::io::duplex(256);
+        let handler = tokio::spawn(async move { handler(server, &config, workers).await });
+
+        let request = b"{\"method\":\"isPrime\",\"number\":7}\n";
+        let (mut r, mut w) = tokio::io::split(client);
+        let writer = tokio::spawn(async move {
+            w.write_all(&request.repeat(1000)).await.unwrap();
+            w.shutdown().await.unwrap();
         });
 
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
+        // the responses are not read, so the requests stop being read too
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!writer.is_finished(), "all the requests have been read");
 
-        let (mut r, mut w) = stream.split();
+        // then everything flows again once the client reads
+        let mut responses = String::new();
+        r.read_to_string(&mut responses).await.unwrap();
+        writer.await.unwrap();
+        handler.await.unwrap().unwrap();
 
-        let payload = br#"{"method":"aaaa","number":11}"#;
-        let written = w.write(payload).await.expect("to write payload");
+        assert_eq!(responses.lines().count(), 1000);
+        assert!(responses
+            .lines()
+            .all(|line| line == r#"{"method":"isPrime","prime":true}"#));
+    }
 
-        w.flush().await.expect("to flush msg");
-        w.shutdown().await.expect("shutdown");
+    #[tokio::test]
+    async fn worker_kept_until_the_job_ends() {
+        let workers = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (end_tx, end_rx) = std::sync::mpsc::channel::<()>();
 
-        assert_ne!(written, 0);
+        let evaluation = Evaluation::spawn(
+            Box::new(move || {
+                started_tx.send(()).unwrap();
+                _ = end_rx.recv();
+                Ok(vec![])
+            }),
+            workers.clone(),
+        );
+        tokio::task::spawn_blocking(move || started_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
 
-        let mut buffer = [0; 1024];
-        let mut last_char = 0;
-        loop {
-            let bytes_read = r.read(&mut buffer).await.expect("read msg");
-            if bytes_read == 0 {
-                break;
-            }
+        // the connection is gone, the job still runs on its worker
+        drop(evaluation);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(workers.available_permits(), 0);
 
-            last_char += bytes_read;
-        }
+        end_tx.send(()).unwrap();
+        let worker = crate::test_support::timeout(workers.acquire()).await;
+        assert!(worker.is_ok());
+    }
+
+    #[tokio::test]
+    async fn several_methods() {
+        let server = TestServer::start(PrimeTime::new()).await;
+        let mut client = server.line_client().await;
+
+        client.send(r#"{"method":"factorize","number":12}"#).await;
+        client.send(r#"{"method":"isPrime","number":12}"#).await;
+        client
+            .send(r#"{"method":"primesInRange","from":1,"to":12}"#)
+            .await;
 
-        let resp = String::from_utf8(buffer[..last_char].to_vec()).unwrap();
-        let expected = r#"error"#;
-        assert_eq!(resp, expected);
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"factorize","factors":[2,2,3]}"#
+        );
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"isPrime","prime":false}"#
+        );
+        assert_eq!(
+            client.recv().await.unwrap(),
+            r#"{"method":"primesInRange","primes":[2,3,5,7,11]}"#
+        );
     }
 }
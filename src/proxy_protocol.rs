@@ -0,0 +1,402 @@
+//! PROXY protocol headers, sent by load balancers at the start of the
+//! connections to tell the address of the client they are forwarding.
+//!
+//! Both the text (v1) and the binary (v2) versions are understood, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use tokio::io::AsyncReadExt;
+
+/// Max time a connection can take to send its header.
+pub const HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Max time a connection can take to start sending its header when it is
+/// optional, balancers send it as soon as they connect.
+pub const FIRST_BYTES_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+/// The longest v1 header, `\r\n` included.
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, family and protocol, length.
+const V2_FIXED_LENGTH: usize = 16;
+
+/// Whether the listeners expect a header on their connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Connections are from the clients, nothing is parsed.
+    #[default]
+    Off,
+    /// Connections may start with a header, the others are from the clients.
+    ///
+    /// The direct clients of services that speak first are served once
+    /// [`FIRST_BYTES_TIMEOUT`] passed without a byte, the bytes that could
+    /// start a header are waited for up to [`HEADER_TIMEOUT`].
+    ///
+    /// Any client could send a header with a forged address, it is meant
+    /// to be used with trusted proxies only.
+    Optional,
+    /// Connections without a header are refused.
+    Required,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Mode::Off),
+            "optional" => Ok(Mode::Optional),
+            "required" => Ok(Mode::Required),
+            _ => Err(format!(
+                "invalid mode `{s}`, expected off, optional or required"
+            )),
+        }
+    }
+}
+
+/// Header at the start of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Header {
+    /// The connection is from the balancer itself, e.g. a health check, or
+    /// the address of the client is unknown.
+    Local,
+    Proxied {
+        source: std::net::SocketAddr,
+        destination: std::net::SocketAddr,
+    },
+}
+
+/// Result of parsing the first bytes of a connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// The bytes could be the start of a header.
+    Incomplete,
+    /// The connection does not start with a header.
+    NotProxy,
+    /// The header and its length in bytes.
+    Header(Header, usize),
+}
+
+/// Parses the header at the start of `buffer`.
+pub fn parse(buffer: &[u8]) -> anyhow::Result<Parsed> {
+    if is_start_of(buffer, V1_SIGNATURE) {
+        return parse_v1(buffer);
+    }
+
+    if is_start_of(buffer, V2_SIGNATURE) {
+        return parse_v2(buffer);
+    }
+
+    Ok(Parsed::NotProxy)
+}
+
+/// Whether the buffer starts with the signature, or could once complete.
+fn is_start_of(buffer: &[u8], signature: &[u8]) -> bool {
+    let n = buffer.len().min(signature.len());
+    buffer[..n] == signature[..n]
+}
+
+fn parse_v1(buffer: &[u8]) -> anyhow::Result<Parsed> {
+    let search = &buffer[..buffer.len().min(V1_MAX_LENGTH)];
+    let Some(end) = search.windows(2).position(|w| w == b"\r\n") else {
+        if buffer.len() >= V1_MAX_LENGTH {
+            anyhow::bail!("v1 header too long");
+        }
+
+        return Ok(Parsed::Incomplete);
+    };
+
+    let line = std::str::from_utf8(&buffer[..end])?;
+    let parts: Vec<_> = line.split(' ').collect();
+
+    let header = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Header::Local,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let source: std::net::IpAddr = source.parse()?;
+            let destination: std::net::IpAddr = destination.parse()?;
+
+            let ipv4 = *family == "TCP4";
+            if source.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+                anyhow::bail!("addresses are not {}", family);
+            }
+
+            Header::Proxied {
+                source: std::net::SocketAddr::new(source, source_port.parse()?),
+                destination: std::net::SocketAddr::new(destination, destination_port.parse()?),
+            }
+        }
+        _ => anyhow::bail!("invalid v1 header: {}", line),
+    };
+
+    Ok(Parsed::Header(header, end + 2))
+}
+
+fn parse_v2(buffer: &[u8]) -> anyhow::Result<Parsed> {
+    if buffer.len() < V2_FIXED_LENGTH {
+        return Ok(Parsed::Incomplete);
+    }
+
+    let version = buffer[12] >> 4;
+    let command = buffer[12] & 0x0f;
+    let family = buffer[13] >> 4;
+    let length = u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+
+    if version != 2 {
+        anyhow::bail!("unsupported version {}", version);
+    }
+
+    let total = V2_FIXED_LENGTH + length;
+    if buffer.len() < total {
+        return Ok(Parsed::Incomplete);
+    }
+
+    let addresses = &buffer[V2_FIXED_LENGTH..total];
+    let header = match (command, family) {
+        // LOCAL
+        (0x0, _) => Header::Local,
+        // PROXY over IPv4
+        (0x1, 0x1) => {
+            if addresses.len() < 12 {
+                anyhow::bail!("IPv4 addresses too short");
+            }
+
+            let ip = |at: usize| -> [u8; 4] { addresses[at..at + 4].try_into().unwrap() };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+            Header::Proxied {
+                source: (ip(0), port(8)).into(),
+                destination: (ip(4), port(10)).into(),
+            }
+        }
+        // PROXY over IPv6
+        (0x1, 0x2) => {
+            if addresses.len() < 36 {
+                anyhow::bail!("IPv6 addresses too short");
+            }
+
+            let ip = |at: usize| -> [u8; 16] { addresses[at..at + 16].try_into().unwrap() };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+
+            Header::Proxied {
+                source: (ip(0), port(32)).into(),
+                destination: (ip(16), port(34)).into(),
+            }
+        }
+        // PROXY with unspecified or unix addresses
+        (0x1, _) => Header::Local,
+        _ => anyhow::bail!("unsupported command {}", command),
+    };
+
+    Ok(Parsed::Header(header, total))
+}
+
+/// Reads the header at the start of the stream into `buffer`, that is left
+/// with the bytes read past it, belonging to the client. Without a header,
+/// all the bytes read are left.
+///
+/// Bytes are kept in `buffer` as they are read, so that the reading can be
+/// cancelled, e.g. by a timeout, and resumed with the same buffer.
+pub async fn read_header<S>(stream: &mut S, buffer: &mut Vec<u8>) -> anyhow::Result<Option<Header>>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    let mut chunk = [0; 512];
+
+    loop {
+        match parse(buffer)? {
+            Parsed::Header(header, length) => {
+                buffer.drain(..length);
+                return Ok(Some(header));
+            }
+            Parsed::NotProxy => return Ok(None),
+            Parsed::Incomplete => (),
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Encodes a v2 header, as a balancer does.
+pub fn encode_v2(header: &Header) -> Vec<u8> {
+    let mut buffer = V2_SIGNATURE.to_vec();
+
+    match header {
+        Header::Local => buffer.extend_from_slice(&[0x20, 0x00, 0, 0]),
+        Header::Proxied {
+            source,
+            destination,
+        } => {
+            let (family, mut addresses) = match (source.ip(), destination.ip()) {
+                (std::net::IpAddr::V4(s), std::net::IpAddr::V4(d)) => {
+                    (0x11, [s.octets(), d.octets()].concat())
+                }
+                (s, d) => {
+                    let v6 = |ip: std::net::IpAddr| match ip {
+                        std::net::IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                        std::net::IpAddr::V6(ip) => ip.octets(),
+                    };
+                    (0x21, [v6(s), v6(d)].concat())
+                }
+            };
+
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+            buffer.extend_from_slice(&[0x21, family]);
+            buffer.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(&addresses);
+        }
+    }
+
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxied(source: &str, destination: &str) -> Header {
+        Header::Proxied {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn parse_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello";
+        assert_eq!(
+            parse(header).unwrap(),
+            Parsed::Header(proxied("192.0.2.1:56324", "198.51.100.1:443"), 45)
+        );
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 8000\r\n";
+        assert_eq!(
+            parse(header).unwrap(),
+            Parsed::Header(
+                proxied("[2001:db8::1]:4000", "[2001:db8::2]:8000"),
+                header.len()
+            )
+        );
+
+        assert_eq!(
+            parse(b"PROXY UNKNOWN\r\n").unwrap(),
+            Parsed::Header(Header::Local, 15)
+        );
+
+        assert_eq!(parse(b"").unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(b"PRO").unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1").unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(b"PROXIMITY").unwrap(), Parsed::NotProxy);
+        assert_eq!(
+            parse(b"{\"method\":\"isPrime\"}\n").unwrap(),
+            Parsed::NotProxy
+        );
+
+        assert!(parse(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 443\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n").is_err());
+        assert!(parse(&[V1_SIGNATURE, &[b'x'; 200]].concat()).is_err());
+    }
+
+    #[test]
+    fn parse_v2() {
+        for header in [
+            proxied("192.0.2.1:56324", "198.51.100.1:443"),
+            proxied("[2001:db8::1]:4000", "[2001:db8::2]:8000"),
+            Header::Local,
+        ] {
+            let encoded = encode_v2(&header);
+            assert_eq!(
+                parse(&encoded).unwrap(),
+                Parsed::Header(header, encoded.len())
+            );
+
+            for n in 0..encoded.len() {
+                assert_eq!(parse(&encoded[..n]).unwrap(), Parsed::Incomplete);
+            }
+        }
+
+        // extra TLVs after the addresses are skipped
+        let mut encoded = encode_v2(&proxied("192.0.2.1:1", "198.51.100.1:2"));
+        encoded[15] += 3;
+        encoded.extend_from_slice(&[0x04, 0x00, 0x00]);
+        assert_eq!(
+            parse(&encoded).unwrap(),
+            Parsed::Header(proxied("192.0.2.1:1", "198.51.100.1:2"), encoded.len())
+        );
+
+        let mut bad_version = encode_v2(&Header::Local);
+        bad_version[12] = 0x10;
+        assert!(parse(&bad_version).is_err());
+
+        let mut short = encode_v2(&proxied("192.0.2.1:1", "198.51.100.1:2"));
+        short[15] = 4;
+        short.truncate(20);
+        assert!(parse(&short).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_header_and_data() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        let mut sent = encode_v2(&proxied("192.0.2.1:56324", "198.51.100.1:443"));
+        sent.extend_from_slice(b"hello");
+        tokio::io::AsyncWriteExt::write_all(&mut client, &sent)
+            .await
+            .unwrap();
+
+        let mut buffer = vec![];
+        let header = read_header(&mut server, &mut buffer).await.unwrap();
+        assert_eq!(header, Some(proxied("192.0.2.1:56324", "198.51.100.1:443")));
+        assert_eq!(buffer, b"hello");
+
+        // without a header, what was read is left
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"hi\n")
+            .await
+            .unwrap();
+        let mut buffer = vec![];
+        let header = read_header(&mut server, &mut buffer).await.unwrap();
+        assert_eq!(header, None);
+        assert_eq!(buffer, b"hi\n");
+    }
+
+    #[tokio::test]
+    async fn resume_read_header() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"PROXY TCP4 192.0.2.1")
+            .await
+            .unwrap();
+
+        // the bytes read before the cancellation are kept
+        let mut buffer = vec![];
+        let read = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            read_header(&mut server, &mut buffer),
+        )
+        .await;
+        assert!(read.is_err());
+        assert_eq!(buffer, b"PROXY TCP4 192.0.2.1");
+
+        tokio::io::AsyncWriteExt::write_all(&mut client, b" 198.51.100.1 56324 443\r\nhi")
+            .await
+            .unwrap();
+        let header = read_header(&mut server, &mut buffer).await.unwrap();
+        assert_eq!(header, Some(proxied("192.0.2.1:56324", "198.51.100.1:443")));
+        assert_eq!(buffer, b"hi");
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!("off".parse::<Mode>(), Ok(Mode::Off));
+        assert_eq!("optional".parse::<Mode>(), Ok(Mode::Optional));
+        assert_eq!("required".parse::<Mode>(), Ok(Mode::Required));
+        assert!("always".parse::<Mode>().is_err());
+    }
+}
@@ -0,0 +1,1149 @@
+//! Common interface of the exercise servers.
+
+use crate::shutdown::Shutdown;
+use tokio::io::AsyncWriteExt;
+use tracing::Instrument;
+
+/// Future returned by the services, so that they can be used as trait objects.
+pub type BoxFuture<T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'static>>;
+
+/// Transport protocol used by a service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Connection handed to the services, counting the traffic for the metrics
+/// and enforcing the timeouts.
+pub type Stream = crate::metrics::Metered<crate::timeouts::Timed<Connection>>;
+
+/// Time given to a refused client to receive the refusal message.
+const REFUSAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Settings the accept loop applies to every connection.
+#[derive(Clone, Default)]
+pub struct Options {
+    pub timeouts: crate::timeouts::Timeouts,
+
+    /// Admission control, shared by the services of the process so that the
+    /// limits apply to all of them together.
+    pub admission: std::sync::Arc<crate::limits::Admission>,
+
+    /// Whether connections start with a PROXY header.
+    pub proxy_protocol: crate::proxy_protocol::Mode,
+
+    /// Addresses of the proxies whose headers are read, any peer when empty.
+    /// The other connections are from the clients: served as they are when
+    /// the header is optional, refused when it is required.
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+impl Options {
+    /// Whether the PROXY header of a connection from `peer` is read, unix
+    /// socket peers are local and always trusted.
+    fn trusts(&self, peer: &Address) -> bool {
+        match peer.ip() {
+            Some(ip) => self.trusted_proxies.is_empty() || self.trusted_proxies.contains(&ip),
+            None => true,
+        }
+    }
+}
+
+/// Address a service listens on, or a client connects from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// IPv4 or IPv6 address, the unspecified IPv6 address `[::]` accepts
+    /// IPv4 clients too.
+    Inet(std::net::SocketAddr),
+    /// Path of a unix domain socket, empty for unnamed clients.
+    Unix(std::path::PathBuf),
+}
+
+impl Address {
+    /// IP of the address, `None` for unix sockets.
+    pub fn ip(&self) -> Option<std::net::IpAddr> {
+        match self {
+            Address::Inet(address) => Some(address.ip()),
+            Address::Unix(_) => None,
+        }
+    }
+}
+
+impl From<std::net::SocketAddr> for Address {
+    fn from(address: std::net::SocketAddr) -> Self {
+        Address::Inet(address)
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    /// Parses `IP:PORT`, `[IPV6]:PORT`, or a path optionally prefixed by
+    /// `unix:`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Address::Unix(path.into()));
+        }
+
+        if s.contains('/') {
+            return Ok(Address::Unix(s.into()));
+        }
+
+        s.parse()
+            .map(Address::Inet)
+            .map_err(|e| format!("invalid address `{s}`: {e}"))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Inet(address) => write!(f, "{address}"),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Connection accepted by a [`Listener`].
+pub struct Connection {
+    socket: Socket,
+    /// Address of the client, the one in the PROXY header if there is one.
+    peer: Address,
+    /// Bytes read ahead while looking for a PROXY header, served first.
+    read_ahead: Vec<u8>,
+}
+
+enum Socket {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl Connection {
+    fn new(socket: Socket, peer: Address) -> Self {
+        Self {
+            socket,
+            peer,
+            read_ahead: vec![],
+        }
+    }
+
+    pub fn peer(&self) -> &Address {
+        &self.peer
+    }
+
+    /// Reads the PROXY header, taking the address of the client from it.
+    ///
+    /// Returns whether there was a header, connections without one are left
+    /// as they are. The bytes read are kept if it is cancelled, and it can be
+    /// called again to resume.
+    async fn read_proxy_header(&mut self) -> anyhow::Result<bool> {
+        let header =
+            crate::proxy_protocol::read_header(&mut self.socket, &mut self.read_ahead).await?;
+
+        match header {
+            Some(crate::proxy_protocol::Header::Proxied { source, .. }) => {
+                self.peer = Address::Inet(source);
+                Ok(true)
+            }
+            Some(crate::proxy_protocol::Header::Local) => Ok(true),
+            None => Ok(false),
+        }
+    }
+}
+
+impl Stream {
+    /// Address of the client.
+    pub fn peer(&self) -> &Address {
+        self.get_ref().get_ref().peer()
+    }
+}
+
+impl tokio::io::AsyncRead for Connection {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_ahead.is_empty() {
+            let n = this.read_ahead.len().min(buf.remaining());
+            buf.put_slice(&this.read_ahead[..n]);
+            this.read_ahead.drain(..n);
+            return std::task::Poll::Ready(Ok(()));
+        }
+
+        std::pin::Pin::new(&mut this.socket).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for Connection {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.get_mut().socket).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().socket).poll_shutdown(cx)
+    }
+}
+
+impl tokio::io::AsyncRead for Socket {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl tokio::io::AsyncWrite for Socket {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => std::pin::Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Socket::Unix(s) => std::pin::Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Socket::Unix(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Socket a service receives its traffic from.
+pub enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Udp(tokio::net::UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds a socket of the given transport to the address, unix sockets
+    /// only serve tcp services.
+    pub async fn bind(transport: Transport, address: impl Into<Address>) -> anyhow::Result<Self> {
+        match address.into() {
+            Address::Inet(address) => {
+                let socket = bind_inet(transport, address)?;
+                Ok(Listener::from_socket(transport, socket)?)
+            }
+            #[cfg(unix)]
+            Address::Unix(path) if transport == Transport::Tcp => {
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            Address::Unix(path) => {
+                anyhow::bail!(
+                    "cannot serve {:?} on unix socket {}",
+                    transport,
+                    path.display()
+                )
+            }
+        }
+    }
+
+    /// Wraps a bound socket, listening already if it is a stream one.
+    pub fn from_socket(transport: Transport, socket: socket2::Socket) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+
+        let listener = match transport {
+            Transport::Tcp => Listener::Tcp(tokio::net::TcpListener::from_std(socket.into())?),
+            Transport::Udp => Listener::Udp(tokio::net::UdpSocket::from_std(socket.into())?),
+        };
+
+        Ok(listener)
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<Address> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(Address::Inet),
+            Listener::Udp(s) => s.local_addr().map(Address::Inet),
+            #[cfg(unix)]
+            Listener::Unix(l) => Ok(Address::Unix(l.path.clone())),
+        }
+    }
+
+    /// Sets the permissions of a unix socket, e.g. `0o660` to only let the
+    /// group connect, other sockets are left alone.
+    pub fn set_permissions(&self, mode: u32) -> std::io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&l.path, std::fs::Permissions::from_mode(mode))
+            }
+            _ => {
+                _ = mode;
+                Ok(())
+            }
+        }
+    }
+
+    /// Accepts the next connection of a stream listener.
+    async fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Listener::Tcp(l) => {
+                let (stream, address) = l.accept().await?;
+                Ok(Connection::new(Socket::Tcp(stream), Address::Inet(address)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                let (stream, address) = l.listener.accept().await?;
+                let path = address.as_pathname().map(|p| p.to_path_buf());
+                Ok(Connection::new(
+                    Socket::Unix(stream),
+                    Address::Unix(path.unwrap_or_default()),
+                ))
+            }
+            Listener::Udp(_) => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+/// Binds a tcp or udp socket, IPv6 ones accepting IPv4 clients too.
+fn bind_inet(
+    transport: Transport,
+    address: std::net::SocketAddr,
+) -> std::io::Result<socket2::Socket> {
+    let kind = match transport {
+        Transport::Tcp => socket2::Type::STREAM,
+        Transport::Udp => socket2::Type::DGRAM,
+    };
+
+    let socket = socket2::Socket::new(socket2::Domain::for_address(address), kind, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+
+    if transport == Transport::Tcp {
+        // same as the std listener, restarts do not wait for old connections
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+    } else {
+        socket.bind(&address.into())?;
+    }
+
+    Ok(socket)
+}
+
+/// Listener of a unix domain socket, removing the socket file when dropped.
+#[cfg(unix)]
+pub struct UnixListener {
+    listener: tokio::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixListener {
+    /// Binds the path, replacing a stale socket left by a server that did
+    /// not stop cleanly but never a file that is not a socket or a socket
+    /// still in use.
+    pub fn bind(path: std::path::PathBuf) -> anyhow::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                anyhow::bail!("{} exists and is not a socket", path.display());
+            }
+
+            match std::os::unix::net::UnixStream::connect(&path) {
+                Ok(_) => anyhow::bail!("{} is used by another server", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    tracing::info!("removing stale socket {}", path.display());
+                    std::fs::remove_file(&path)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(Self {
+            listener: tokio::net::UnixListener::bind(&path)?,
+            path,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Identifier of the next accepted connection, unique in the process so that
+/// the logs of a session can be told apart even across services.
+fn next_connection_id() -> u64 {
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// A protohackers exercise server.
+///
+/// TCP services only need to implement `handle`, that is called for every
+/// accepted connection by the default `run`. UDP services, or services that
+/// need their own accept loop, override `run`.
+pub trait Service: Send + Sync + 'static {
+    /// Name used in logs.
+    fn name(&self) -> &'static str;
+
+    fn transport(&self) -> Transport {
+        Transport::Tcp
+    }
+
+    /// Handles a single accepted connection.
+    ///
+    /// Long-lived connections can watch `shutdown` to say goodbye to the
+    /// client, the others are left to complete while the server drains.
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: Stream,
+        shutdown: Shutdown,
+    ) -> BoxFuture<anyhow::Result<()>> {
+        _ = (stream, shutdown);
+        let name = self.name();
+        Box::pin(async move { anyhow::bail!("{name} does not handle tcp connections") })
+    }
+
+    /// Message sent to a client refused by the limits before closing the
+    /// connection, by default it is closed straight away.
+    fn refusal(&self, reason: crate::limits::Refusal) -> Option<Vec<u8>> {
+        _ = reason;
+        None
+    }
+
+    /// Serves all the traffic coming from the listener until `shutdown` is
+    /// triggered, applying `options` to the connections.
+    fn run(
+        self: std::sync::Arc<Self>,
+        listener: Listener,
+        options: Options,
+        shutdown: Shutdown,
+    ) -> BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move {
+            if let Listener::Udp(_) = listener {
+                anyhow::bail!("{} needs a tcp listener", self.name());
+            }
+
+            let metrics = crate::metrics::ConnectionMetrics::new(self.name());
+            let mut connections = tokio::task::JoinSet::new();
+
+            loop {
+                let connection = tokio::select! {
+                    _ = shutdown.wait() => break,
+
+                    // reap the completed connections
+                    Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+
+                    accepted = listener.accept() => accepted?,
+                };
+
+                let span = tracing::info_span!(
+                    "connection",
+                    service = self.name(),
+                    peer = %connection.peer(),
+                    client = tracing::field::Empty,
+                    id = next_connection_id(),
+                );
+                span.in_scope(|| tracing::info!("connection received"));
+
+                connections.spawn(
+                    serve_connection(
+                        self.clone(),
+                        connection,
+                        options.clone(),
+                        metrics.clone(),
+                        shutdown.clone(),
+                    )
+                    .instrument(span),
+                );
+            }
+
+            tracing::info!("{} stopped accepting connections", self.name());
+            drop(listener);
+            shutdown.drain(connections).await;
+
+            Ok(())
+        })
+    }
+}
+
+/// Serves a connection accepted by the default `run`: checks the limits,
+/// takes the client address from the PROXY header if one is expected and
+/// hands it to the service.
+async fn serve_connection<S: Service + ?Sized>(
+    service: std::sync::Arc<S>,
+    mut connection: Connection,
+    options: Options,
+    metrics: crate::metrics::ConnectionMetrics,
+    shutdown: Shutdown,
+) {
+    use crate::proxy_protocol::Mode;
+
+    // headers are only read from the proxies, a client could otherwise
+    // forge the address it connects from
+    let mode = match options.proxy_protocol {
+        Mode::Off => Mode::Off,
+        _ if options.trusts(connection.peer()) => options.proxy_protocol,
+        Mode::Optional => Mode::Off,
+        Mode::Required => {
+            tracing::warn!("connection refused: not from a trusted proxy");
+            metrics.refused.inc();
+            return;
+        }
+    };
+
+    // the address of a proxied client is only known once its header is
+    // read, the limit of its address is checked then
+    let ip = match mode {
+        Mode::Off => connection.peer().ip(),
+        _ => None,
+    };
+    let mut permit = match options.admission.admit(ip) {
+        Ok(permit) => permit,
+        Err(reason) => return refuse(&*service, connection, reason, &metrics).await,
+    };
+
+    if mode != Mode::Off {
+        if let Err(reason) = proxy_header(&mut connection, mode).await {
+            tracing::warn!("connection refused: {}", reason);
+            metrics.refused.inc();
+            return;
+        }
+
+        tracing::Span::current().record("client", tracing::field::display(connection.peer()));
+
+        let ip = connection.peer().ip();
+        if let Err(reason) = options.admission.admit_ip(&mut permit, ip) {
+            return refuse(&*service, connection, reason, &metrics).await;
+        }
+    }
+
+    metrics.accepted.inc();
+    let _active = metrics.active.track();
+    let stream = crate::timeouts::Timed::new(connection, options.timeouts);
+    let stream = crate::metrics::Metered::new(stream, &metrics);
+
+    match service.handle(stream, shutdown).await {
+        Ok(_) => tracing::info!("connection closed"),
+        Err(e) => match e
+            .downcast_ref::<std::io::Error>()
+            .and_then(crate::timeouts::Expired::from_io)
+        {
+            Some(expired) => tracing::info!("connection closed: {}", expired),
+            None => tracing::error!("error on handling connection: {}", e),
+        },
+    }
+}
+
+/// Reads the PROXY header of the connection, the error is why the
+/// connection is refused.
+async fn proxy_header(
+    connection: &mut Connection,
+    mode: crate::proxy_protocol::Mode,
+) -> Result<(), String> {
+    use crate::proxy_protocol::{Mode, FIRST_BYTES_TIMEOUT, HEADER_TIMEOUT};
+
+    let wait = match mode {
+        Mode::Optional => FIRST_BYTES_TIMEOUT,
+        _ => HEADER_TIMEOUT,
+    };
+    let mut header = tokio::time::timeout(wait, connection.read_proxy_header()).await;
+
+    // the first bytes could be a header, it is given the time of the others
+    if header.is_err() && !connection.read_ahead.is_empty() && wait < HEADER_TIMEOUT {
+        header = tokio::time::timeout(HEADER_TIMEOUT - wait, connection.read_proxy_header()).await;
+    }
+
+    match (header, mode) {
+        (Ok(Ok(true)), _) | (Ok(Ok(false)), Mode::Optional) => Ok(()),
+        // direct clients that wait for the server to speak first, or that
+        // stopped in the middle of what looked like a header, are served
+        // with what they sent
+        (Err(_), Mode::Optional) => Ok(()),
+        (Ok(Ok(false)), _) => Err("no PROXY header".to_string()),
+        (Ok(Err(e)), _) => Err(format!("invalid PROXY header: {e}")),
+        (Err(_), _) => Err("no PROXY header in time".to_string()),
+    }
+}
+
+/// Refuses a connection over the limits, telling the client why if the
+/// service has something to say.
+async fn refuse<S: Service + ?Sized>(
+    service: &S,
+    mut connection: Connection,
+    reason: crate::limits::Refusal,
+    metrics: &crate::metrics::ConnectionMetrics,
+) {
+    tracing::warn!("connection refused: {}", reason);
+    metrics.refused.inc();
+
+    if let Some(message) = service.refusal(reason) {
+        _ = tokio::time::timeout(REFUSAL_TIMEOUT, async {
+            connection.write_all(&message).await?;
+            connection.shutdown().await
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn shutdown() -> Shutdown {
+        Shutdown::new(std::time::Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn transport_mismatch() {
+        let socket = Listener::bind(Transport::Udp, "127.0.0.1:0".parse::<Address>().unwrap())
+            .await
+            .expect("bind udp socket");
+
+        let result = std::sync::Arc::new(crate::smoke_test::SmokeTest)
+            .run(socket, Options::default(), shutdown())
+            .await;
+        assert!(result.is_err());
+
+        let listener = Listener::bind(Transport::Tcp, "127.0.0.1:0".parse::<Address>().unwrap())
+            .await
+            .expect("bind tcp listener");
+
+        let result = std::sync::Arc::new(crate::unusual_db::UnusualDb::new())
+            .run(listener, Options::default(), shutdown())
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_in_flight_connections() {
+        let listener = Listener::bind(Transport::Tcp, "127.0.0.1:0".parse::<Address>().unwrap())
+            .await
+            .expect("bind tcp listener");
+        let Ok(Address::Inet(local_addr)) = listener.local_addr() else {
+            panic!("listener has an inet address");
+        };
+
+        let shutdown = shutdown();
+        let server = tokio::spawn(std::sync::Arc::new(crate::smoke_test::SmokeTest).run(
+            listener,
+            Options::default(),
+            shutdown.clone(),
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(local_addr)
+            .await
+            .expect("connection with local works");
+        stream.write_all(b"before").await.expect("to write payload");
+
+        let mut buffer = [0; 6];
+        stream.read_exact(&mut buffer).await.expect("read echo");
+        assert_eq!(&buffer, b"before");
+
+        shutdown.trigger();
+
+        // the connection accepted before the shutdown is still served
+        stream.write_all(b"during").await.expect("to write payload");
+        stream.read_exact(&mut buffer).await.expect("read echo");
+        assert_eq!(&buffer, b"during");
+
+        stream.shutdown().await.expect("shutdown");
+        server
+            .await
+            .expect("server task completes")
+            .expect("server stops cleanly");
+
+        assert!(tokio::net::TcpStream::connect(local_addr).await.is_err());
+    }
+
+    /// Echo service with its own name, so that its metrics are not touched
+    /// by other tests.
+    struct MeteredEcho;
+
+    impl Service for MeteredEcho {
+        fn name(&self) -> &'static str {
+            "metered_echo"
+        }
+
+        fn handle(
+            self: std::sync::Arc<Self>,
+            stream: Stream,
+            _shutdown: Shutdown,
+        ) -> BoxFuture<anyhow::Result<()>> {
+            Box::pin(crate::smoke_test::handler(stream))
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_metrics() {
+        let server = crate::test_support::TestServer::start(MeteredEcho).await;
+        let metrics = crate::metrics::ConnectionMetrics::new("metered_echo");
+
+        let mut client = server.binary_client().await;
+        client.send(b"hello").await;
+        assert_eq!(client.recv_exact(5).await, b"hello");
+
+        assert_eq!(metrics.accepted.get(), 1);
+        assert_eq!(metrics.active.get(), 1);
+        assert_eq!(metrics.received.get(), 5);
+        assert_eq!(metrics.sent.get(), 5);
+
+        client.shutdown().await;
+        assert!(client.recv_to_end().await.is_empty());
+        server.stop().await.expect("server stops cleanly");
+
+        assert_eq!(metrics.active.get(), 0);
+    }
+
+    /// Echo service of the limits test, with its own metrics.
+    struct LimitedEcho;
+
+    impl Service for LimitedEcho {
+        fn name(&self) -> &'static str {
+            "limited_echo"
+        }
+
+        fn handle(
+            self: std::sync::Arc<Self>,
+            stream: Stream,
+            _shutdown: Shutdown,
+        ) -> BoxFuture<anyhow::Result<()>> {
+            Box::pin(crate::smoke_test::handler(stream))
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_over_the_limits() {
+        let options = Options {
+            admission: std::sync::Arc::new(crate::limits::Admission::new(crate::limits::Limits {
+                max_connections: Some(1),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let server =
+            crate::test_support::TestServer::start_with(std::sync::Arc::new(LimitedEcho), options)
+                .await;
+        let metrics = crate::metrics::ConnectionMetrics::new("limited_echo");
+
+        let mut first = server.binary_client().await;
+        first.send(b"hello").await;
+        assert_eq!(first.recv_exact(5).await, b"hello");
+
+        // binary protocols close the connection without a word
+        let mut second = server.binary_client().await;
+        assert!(second.recv_to_end().await.is_empty());
+        assert_eq!(metrics.refused.get(), 1);
+        assert_eq!(metrics.accepted.get(), 1);
+
+        // the admitted client is still served
+        first.send(b"again").await;
+        assert_eq!(first.recv_exact(5).await, b"again");
+    }
+
+    async fn echo<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(stream: &mut S) {
+        stream.write_all(b"hello").await.expect("to write payload");
+
+        let mut buffer = [0; 5];
+        stream.read_exact(&mut buffer).await.expect("read echo");
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[tokio::test]
+    async fn dual_stack() {
+        let listener = Listener::bind(Transport::Tcp, "[::]:0".parse::<Address>().unwrap())
+            .await
+            .expect("bind tcp listener");
+        let Ok(Address::Inet(local_addr)) = listener.local_addr() else {
+            panic!("listener has an inet address");
+        };
+
+        let shutdown = shutdown();
+        let server = tokio::spawn(std::sync::Arc::new(crate::smoke_test::SmokeTest).run(
+            listener,
+            Options::default(),
+            shutdown.clone(),
+        ));
+
+        for ip in ["127.0.0.1", "::1"] {
+            let address = std::net::SocketAddr::new(ip.parse().unwrap(), local_addr.port());
+            let mut stream = tokio::net::TcpStream::connect(address)
+                .await
+                .expect("connection with local works");
+            echo(&mut stream).await;
+        }
+
+        shutdown.trigger();
+        server
+            .await
+            .expect("server task completes")
+            .expect("server stops cleanly");
+    }
+
+    /// Path of a unix socket in the temporary directory, unique to the test.
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("protohackers-{}-{name}.sock", std::process::id()))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = socket_path("unix_socket");
+        let listener = Listener::bind(Transport::Tcp, Address::Unix(path.clone()))
+            .await
+            .expect("bind unix socket");
+        listener.set_permissions(0o600).expect("set permissions");
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // a socket in use is not replaced
+        assert!(Listener::bind(Transport::Tcp, Address::Unix(path.clone()))
+            .await
+            .is_err());
+
+        let shutdown = shutdown();
+        let server = tokio::spawn(std::sync::Arc::new(crate::smoke_test::SmokeTest).run(
+            listener,
+            Options::default(),
+            shutdown.clone(),
+        ));
+
+        let mut stream = tokio::net::UnixStream::connect(&path)
+            .await
+            .expect("connection with local works");
+        echo(&mut stream).await;
+        stream.shutdown().await.expect("shutdown");
+
+        shutdown.trigger();
+        server
+            .await
+            .expect("server task completes")
+            .expect("server stops cleanly");
+
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stale_unix_socket() {
+        let path = socket_path("stale_unix_socket");
+
+        // a server that did not stop cleanly leaves its socket behind
+        drop(std::os::unix::net::UnixListener::bind(&path).expect("bind unix socket"));
+        assert!(path.exists());
+
+        let listener = Listener::bind(Transport::Tcp, Address::Unix(path.clone()))
+            .await
+            .expect("stale socket is replaced");
+        drop(listener);
+        assert!(!path.exists());
+
+        // files that are not sockets are left alone
+        std::fs::write(&path, b"data").unwrap();
+        assert!(Listener::bind(Transport::Tcp, Address::Unix(path.clone()))
+            .await
+            .is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+
+        // datagram services cannot use unix sockets
+        assert!(Listener::bind(Transport::Udp, Address::Unix(path.clone()))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            "[::]:8000".parse::<Address>(),
+            Ok(Address::Inet("[::]:8000".parse().unwrap()))
+        );
+        assert_eq!(
+            "/run/echo.sock".parse::<Address>(),
+            Ok(Address::Unix("/run/echo.sock".into()))
+        );
+        assert_eq!(
+            "unix:echo.sock".parse::<Address>(),
+            Ok(Address::Unix("echo.sock".into()))
+        );
+        assert!("localhost".parse::<Address>().is_err());
+
+        assert_eq!(
+            Address::Unix("echo.sock".into()).to_string(),
+            "unix:echo.sock"
+        );
+        assert_eq!(Address::Unix("echo.sock".into()).ip(), None);
+    }
+
+    /// Tells the client its address, then echoes, with the name of the test
+    /// for its metrics.
+    struct PeerEcho(&'static str);
+
+    impl Service for PeerEcho {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn handle(
+            self: std::sync::Arc<Self>,
+            mut stream: Stream,
+            _shutdown: Shutdown,
+        ) -> BoxFuture<anyhow::Result<()>> {
+            Box::pin(async move {
+                let peer = format!("{}\n", stream.peer());
+                stream.write_all(peer.as_bytes()).await?;
+                crate::smoke_test::handler(stream).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol() {
+        use crate::proxy_protocol::{encode_v2, Header, Mode};
+
+        let options = Options {
+            admission: std::sync::Arc::new(crate::limits::Admission::new(crate::limits::Limits {
+                max_connections_per_ip: Some(1),
+                ..Default::default()
+            })),
+            proxy_protocol: Mode::Required,
+            ..Default::default()
+        };
+        let server = crate::test_support::TestServer::start_with(
+            std::sync::Arc::new(PeerEcho("proxy_protocol")),
+            options,
+        )
+        .await;
+        let metrics = crate::metrics::ConnectionMetrics::new("proxy_protocol");
+
+        let v2 = |source: &str| {
+            encode_v2(&Header::Proxied {
+                source: source.parse().unwrap(),
+                destination: "198.51.100.1:8000".parse().unwrap(),
+            })
+        };
+
+        // the data sent with the header reaches the service
+        let mut first = server.line_client().await;
+        first
+            .send_raw(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 8000\r\nhello\n")
+            .await;
+        assert_eq!(first.recv().await.unwrap(), "192.0.2.1:56324");
+        assert_eq!(first.recv().await.unwrap(), "hello");
+
+        // the limits apply to the address of the client
+        let mut same_client = server.line_client().await;
+        same_client.send_raw(&v2("192.0.2.1:56325")).await;
+        assert_eq!(same_client.recv().await, None);
+
+        let mut other_client = server.line_client().await;
+        other_client.send_raw(&v2("[2001:db8::1]:4000")).await;
+        assert_eq!(other_client.recv().await.unwrap(), "[2001:db8::1]:4000");
+
+        // connections without a header are refused
+        let mut direct = server.line_client().await;
+        direct.send("hello").await;
+        assert_eq!(direct.recv().await, None);
+
+        assert_eq!(metrics.refused.get(), 2);
+        assert_eq!(metrics.accepted.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn optional_proxy_protocol() {
+        let options = Options {
+            proxy_protocol: crate::proxy_protocol::Mode::Optional,
+            ..Default::default()
+        };
+        let server = crate::test_support::TestServer::start_with(
+            std::sync::Arc::new(PeerEcho("optional_proxy_protocol")),
+            options,
+        )
+        .await;
+
+        let mut proxied = server.line_client().await;
+        proxied
+            .send_raw(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 8000\r\n")
+            .await;
+        assert_eq!(proxied.recv().await.unwrap(), "[2001:db8::1]:4000");
+
+        // a client sending first is served straight away
+        let mut direct = server.line_client().await;
+        direct.send("hello").await;
+        assert!(direct.recv().await.unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(direct.recv().await.unwrap(), "hello");
+        assert_eq!(
+            crate::metrics::ConnectionMetrics::new("optional_proxy_protocol")
+                .refused
+                .get(),
+            0
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn optional_proxy_protocol_waits() {
+        use crate::proxy_protocol::{FIRST_BYTES_TIMEOUT, HEADER_TIMEOUT};
+
+        let options = Options {
+            proxy_protocol: crate::proxy_protocol::Mode::Optional,
+            ..Default::default()
+        };
+        let server = crate::test_support::TestServer::start_with(
+            std::sync::Arc::new(PeerEcho("optional_proxy_protocol_waits")),
+            options,
+        )
+        .await;
+
+        // a client waiting for the server to speak first is not kept waiting
+        // for a header
+        let start = tokio::time::Instant::now();
+        let mut silent = server.line_client().await;
+        assert!(silent.recv().await.unwrap().starts_with("127.0.0.1:"));
+        assert!(start.elapsed() < HEADER_TIMEOUT);
+
+        // what looked like the start of a header is given more time, and
+        // served to the service once it is not one
+        let mut partial = server.line_client().await;
+        partial.send_raw(b"PROXY TCP4 192.0.2.1").await;
+        tokio::time::sleep(FIRST_BYTES_TIMEOUT * 2).await;
+        partial.send_raw(b" 198.51.100").await;
+
+        tokio::time::sleep(HEADER_TIMEOUT).await;
+        assert!(partial.recv().await.unwrap().starts_with("127.0.0.1:"));
+
+        partial.send_raw(b"\n").await;
+        assert_eq!(
+            partial.recv().await.unwrap(),
+            "PROXY TCP4 192.0.2.1 198.51.100"
+        );
+    }
+
+    #[tokio::test]
+    async fn untrusted_proxy() {
+        use crate::proxy_protocol::Mode;
+
+        let start = |name, proxy_protocol, proxy: &str| {
+            let options = Options {
+                proxy_protocol,
+                trusted_proxies: vec![proxy.parse().unwrap()],
+                ..Default::default()
+            };
+            crate::test_support::TestServer::start_with(
+                std::sync::Arc::new(PeerEcho(name)),
+                options,
+            )
+        };
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 8000\r\n";
+
+        // the header of a direct client is data, its address is kept
+        let optional = start("untrusted_proxy_optional", Mode::Optional, "192.0.2.9").await;
+        let mut direct = optional.line_client().await;
+        direct.send_raw(header).await;
+        assert!(direct.recv().await.unwrap().starts_with("127.0.0.1:"));
+        assert!(direct
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("PROXY TCP4 192.0.2.1"));
+
+        let required = start("untrusted_proxy_required", Mode::Required, "192.0.2.9").await;
+        let mut direct = required.line_client().await;
+        assert_eq!(direct.recv().await, None);
+        assert_eq!(
+            crate::metrics::ConnectionMetrics::new("untrusted_proxy_required")
+                .refused
+                .get(),
+            1
+        );
+
+        let trusted = start("untrusted_proxy_trusted", Mode::Required, "127.0.0.1").await;
+        let mut proxy = trusted.line_client().await;
+        proxy.send_raw(header).await;
+        assert_eq!(proxy.recv().await.unwrap(), "192.0.2.1:56324");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn admit_before_proxy_header() {
+        let options = Options {
+            admission: std::sync::Arc::new(crate::limits::Admission::new(crate::limits::Limits {
+                max_connections: Some(1),
+                ..Default::default()
+            })),
+            proxy_protocol: crate::proxy_protocol::Mode::Required,
+            ..Default::default()
+        };
+        let server = crate::test_support::TestServer::start_with(
+            std::sync::Arc::new(PeerEcho("admit_before_proxy_header")),
+            options,
+        )
+        .await;
+
+        let mut first = server.line_client().await;
+        first
+            .send_raw(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 8000\r\n")
+            .await;
+        assert_eq!(first.recv().await.unwrap(), "192.0.2.1:56324");
+
+        // refused straight away, without waiting for a header
+        let start = tokio::time::Instant::now();
+        let mut second = server.line_client().await;
+        assert_eq!(second.recv().await, None);
+        assert!(start.elapsed() < crate::proxy_protocol::HEADER_TIMEOUT);
+    }
+}
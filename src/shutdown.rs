@@ -0,0 +1,111 @@
+//! Graceful shutdown of the servers.
+
+/// Shutdown signal shared by all the servers and their connections.
+///
+/// Once triggered, servers stop accepting new connections and wait up to
+/// `drain_timeout` for the in-flight ones before giving up on them.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    tx: std::sync::Arc<tokio::sync::watch::Sender<bool>>,
+    drain_timeout: std::time::Duration,
+}
+
+impl Shutdown {
+    pub fn new(drain_timeout: std::time::Duration) -> Self {
+        let (tx, _) = tokio::sync::watch::channel(false);
+
+        Self {
+            tx: std::sync::Arc::new(tx),
+            drain_timeout,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Resolves once the shutdown has been triggered.
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        _ = rx.wait_for(|&triggered| triggered).await;
+    }
+
+    /// Waits for the connection tasks to complete, aborting them when the
+    /// drain timeout expires.
+    pub async fn drain(&self, mut connections: tokio::task::JoinSet<()>) {
+        if connections.is_empty() {
+            return;
+        }
+
+        tracing::info!(
+            "waiting up to {:?} for {} connections to complete",
+            self.drain_timeout,
+            connections.len()
+        );
+
+        let all_done = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.drain_timeout, all_done)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "drain timeout expired, dropping {} connections",
+                connections.len()
+            );
+            connections.shutdown().await;
+        }
+    }
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+#[cfg(unix)]
+pub async fn signal() -> std::io::Result<()> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Resolves when the process receives Ctrl-C.
+#[cfg(not(unix))]
+pub async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn trigger_wakes_up_waiters() {
+        let shutdown = Shutdown::new(std::time::Duration::from_secs(1));
+
+        let waiter = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { shutdown.wait().await })
+        };
+
+        shutdown.trigger();
+        waiter.await.expect("waiter completes");
+
+        // waiting after the trigger returns immediately
+        shutdown.wait().await;
+    }
+
+    #[tokio::test]
+    async fn drain_aborts_after_timeout() {
+        let shutdown = Shutdown::new(std::time::Duration::from_millis(50));
+
+        let mut connections = tokio::task::JoinSet::new();
+        connections.spawn(std::future::pending::<()>());
+
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            shutdown.drain(connections),
+        )
+        .await
+        .expect("drain respects the timeout");
+    }
+}
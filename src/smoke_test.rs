@@ -1,6 +1,27 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
+/// Problem 0: echoes back everything it receives.
+pub struct SmokeTest;
+
+impl crate::service::Service for SmokeTest {
+    fn name(&self) -> &'static str {
+        "smoke_test"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(handler(stream))
+    }
+}
+
+/// Serves a single client until it closes the connection.
+pub async fn handler<S>(mut stream: S) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
     loop {
         let mut buffer = [0; 1024];
         match stream.read(&mut buffer).await? {
@@ -15,57 +36,32 @@ pub async fn handler(mut stream: tokio::net::TcpStream) -> anyhow::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::test_support::TestServer;
 
     #[tokio::test]
     async fn echo() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
-            .await
-            .expect("open a listener");
-
-        let local_addr = listener.local_addr().expect("local address works");
-
-        tokio::spawn(async move {
-            loop {
-                let stream = match listener.accept().await {
-                    Ok((stream, address)) => {
-                        println!("connection received for {}", address);
-
-                        stream
-                    }
-                    Err(_) => panic!("error on accepting connection"),
-                };
+        let server = TestServer::start(SmokeTest).await;
+        let mut client = server.binary_client().await;
 
-                tokio::spawn(handler(stream));
-            }
-        });
+        client.send(b"hello echo server\n").await;
+        client.shutdown().await;
 
-        let mut stream = tokio::net::TcpStream::connect(local_addr)
-            .await
-            .expect("connection with local works");
-
-        let (mut r, mut w) = stream.split();
-
-        let written = w
-            .write(b"hello echo server\n")
-            .await
-            .expect("to write payload");
-        w.flush().await.expect("to flush msg");
-        w.shutdown().await.expect("shutdown");
-
-        assert_ne!(written, 0);
-
-        let mut buffer = [0; 1024];
-        let mut last_char = 0;
-        loop {
-            let bytes_read = r.read(&mut buffer).await.expect("read msg");
-            if bytes_read == 0 {
-                break;
-            }
+        assert_eq!(client.recv_to_end().await, b"hello echo server\n");
+    }
 
-            last_char += bytes_read;
-        }
+    #[tokio::test]
+    async fn idle_client() {
+        let options = crate::service::Options {
+            timeouts: crate::timeouts::Timeouts {
+                idle: Some(std::time::Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = TestServer::start_with(std::sync::Arc::new(SmokeTest), options).await;
+        let mut client = server.binary_client().await;
 
-        assert_eq!(&buffer[..last_char], b"hello echo server\n");
+        // a client that never sends anything is disconnected
+        assert!(client.recv_to_end().await.is_empty());
     }
 }
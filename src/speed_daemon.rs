@@ -0,0 +1,541 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SECONDS_PER_DAY: u32 = 86400;
+
+/// Messages sent by cameras and dispatchers.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    Plate { plate: String, timestamp: u32 },
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<u16> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    /// Average speed in hundredths of miles per hour.
+    pub speed: u16,
+}
+
+/// Messages sent by the server.
+#[derive(Debug)]
+pub enum ServerMessage {
+    Error { msg: String },
+    Ticket(Ticket),
+    Heartbeat,
+}
+
+impl ServerMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+
+        match self {
+            ServerMessage::Error { msg } => {
+                buffer.push(0x10);
+                put_str(&mut buffer, msg);
+            }
+            ServerMessage::Ticket(t) => {
+                buffer.push(0x21);
+                put_str(&mut buffer, &t.plate);
+                buffer.extend_from_slice(&t.road.to_be_bytes());
+                buffer.extend_from_slice(&t.mile1.to_be_bytes());
+                buffer.extend_from_slice(&t.timestamp1.to_be_bytes());
+                buffer.extend_from_slice(&t.mile2.to_be_bytes());
+                buffer.extend_from_slice(&t.timestamp2.to_be_bytes());
+                buffer.extend_from_slice(&t.speed.to_be_bytes());
+            }
+            ServerMessage::Heartbeat => buffer.push(0x41),
+        }
+
+        buffer
+    }
+}
+
+fn put_str(buffer: &mut Vec<u8>, s: &str) {
+    // strings are at most 255 bytes long, anything longer is truncated
+    let bytes = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    buffer.push(bytes.len() as u8);
+    buffer.extend_from_slice(bytes);
+}
+
+async fn read_str<R: tokio::io::AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<String> {
+    let len = r.read_u8().await?;
+    let mut buffer = vec![0; len as usize];
+    r.read_exact(&mut buffer).await?;
+
+    Ok(String::from_utf8_lossy(&buffer).to_string())
+}
+
+/// Reads the next message sent by the client, `None` means that the client
+/// closed the connection.
+pub async fn read_message<R: tokio::io::AsyncRead + Unpin>(
+    r: &mut R,
+) -> anyhow::Result<Option<ClientMessage>> {
+    let kind = match r.read_u8().await {
+        Ok(kind) => kind,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let msg = match kind {
+        0x20 => ClientMessage::Plate {
+            plate: read_str(r).await?,
+            timestamp: r.read_u32().await?,
+        },
+        0x40 => ClientMessage::WantHeartbeat {
+            interval: r.read_u32().await?,
+        },
+        0x80 => ClientMessage::IAmCamera {
+            road: r.read_u16().await?,
+            mile: r.read_u16().await?,
+            limit: r.read_u16().await?,
+        },
+        0x81 => {
+            let n = r.read_u8().await?;
+            let mut roads = Vec::with_capacity(n as usize);
+            for _ in 0..n {
+                roads.push(r.read_u16().await?);
+            }
+
+            ClientMessage::IAmDispatcher { roads }
+        }
+        _ => anyhow::bail!("illegal msg"),
+    };
+
+    Ok(Some(msg))
+}
+
+type Outgoing = tokio::sync::mpsc::UnboundedSender<ServerMessage>;
+
+#[derive(Default)]
+struct State {
+    /// Speed limit of every road, in miles per hour.
+    limits: std::collections::HashMap<u16, u16>,
+
+    /// Observations of a plate on a road, as timestamp -> mile.
+    observations: std::collections::HashMap<(String, u16), std::collections::BTreeMap<u32, u16>>,
+
+    /// Days in which a plate already got a ticket.
+    ticketed: std::collections::HashMap<String, std::collections::HashSet<u32>>,
+
+    dispatchers: std::collections::HashMap<u16, Vec<(u64, Outgoing)>>,
+
+    /// Tickets waiting for a dispatcher responsible for their road.
+    pending: std::collections::HashMap<u16, Vec<Ticket>>,
+
+    next_dispatcher: u64,
+}
+
+impl State {
+    /// Records a plate observation and returns the tickets it causes.
+    fn observe(&mut self, plate: &str, road: u16, mile: u16, timestamp: u32) -> Vec<Ticket> {
+        let Some(&limit) = self.limits.get(&road) else {
+            return vec![];
+        };
+
+        let seen = self
+            .observations
+            .entry((plate.to_string(), road))
+            .or_default();
+        seen.insert(timestamp, mile);
+
+        // checking only the closest observations is enough: when a car speeds
+        // between two far observations it also does between two adjacent ones
+        let before = seen.range(..timestamp).next_back();
+        let after = seen
+            .range((
+                std::ops::Bound::Excluded(timestamp),
+                std::ops::Bound::Unbounded,
+            ))
+            .next();
+
+        let candidates = [
+            before.map(|(&t, &m)| ((t, m), (timestamp, mile))),
+            after.map(|(&t, &m)| ((timestamp, mile), (t, m))),
+        ];
+
+        let mut tickets = vec![];
+        for ((timestamp1, mile1), (timestamp2, mile2)) in candidates.into_iter().flatten() {
+            let distance = mile1.abs_diff(mile2) as f64;
+            let hours = (timestamp2 - timestamp1) as f64 / 3600.0;
+            let speed = distance / hours;
+
+            if speed < limit as f64 + 0.5 {
+                continue;
+            }
+
+            let days = timestamp1 / SECONDS_PER_DAY..=timestamp2 / SECONDS_PER_DAY;
+            let ticketed = self.ticketed.entry(plate.to_string()).or_default();
+            if days.clone().any(|d| ticketed.contains(&d)) {
+                tracing::info!("{} already has a ticket for the day", plate);
+                continue;
+            }
+            ticketed.extend(days);
+
+            tickets.push(Ticket {
+                plate: plate.to_string(),
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed: (speed * 100.0).round().min(u16::MAX as f64) as u16,
+            });
+        }
+
+        tickets
+    }
+
+    /// Sends the ticket to a dispatcher of the road, or keeps it until one
+    /// connects.
+    fn dispatch(&mut self, ticket: Ticket) {
+        let road = ticket.road;
+        let mut msg = ServerMessage::Ticket(ticket);
+
+        if let Some(dispatchers) = self.dispatchers.get_mut(&road) {
+            while let Some((_, tx)) = dispatchers.first() {
+                match tx.send(msg) {
+                    Ok(_) => return,
+                    Err(e) => {
+                        // the dispatcher went away, try with the next one
+                        msg = e.0;
+                        dispatchers.remove(0);
+                    }
+                }
+            }
+        }
+
+        if let ServerMessage::Ticket(ticket) = msg {
+            tracing::info!("no dispatcher for road {}, ticket queued", ticket.road);
+            self.pending.entry(ticket.road).or_default().push(ticket);
+        }
+    }
+
+    fn add_dispatcher(&mut self, roads: &[u16], tx: Outgoing) -> u64 {
+        let id = self.next_dispatcher;
+        self.next_dispatcher += 1;
+
+        for road in roads {
+            self.dispatchers
+                .entry(*road)
+                .or_default()
+                .push((id, tx.clone()));
+
+            for ticket in self.pending.remove(road).unwrap_or_default() {
+                self.dispatch(ticket);
+            }
+        }
+
+        id
+    }
+
+    fn remove_dispatcher(&mut self, id: u64) {
+        for dispatchers in self.dispatchers.values_mut() {
+            dispatchers.retain(|(i, _)| *i != id);
+        }
+    }
+}
+
+/// Removes the dispatcher from the state when the connection goes away.
+struct DispatcherGuard {
+    id: u64,
+    state: std::sync::Arc<std::sync::Mutex<State>>,
+}
+
+impl Drop for DispatcherGuard {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().remove_dispatcher(self.id);
+    }
+}
+
+/// Stops the heartbeat task when the connection goes away.
+struct HeartbeatGuard(tokio::task::JoinHandle<()>);
+
+impl Drop for HeartbeatGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Unknown,
+    Camera { road: u16, mile: u16 },
+    Dispatcher,
+}
+
+/// Problem 6: issues tickets to the cars going over the speed limit.
+pub struct SpeedDaemon {
+    state: std::sync::Arc<std::sync::Mutex<State>>,
+}
+
+impl SpeedDaemon {
+    pub fn new() -> Self {
+        Self {
+            state: std::sync::Arc::new(std::sync::Mutex::new(State::default())),
+        }
+    }
+}
+
+impl Default for SpeedDaemon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl crate::service::Service for SpeedDaemon {
+    fn name(&self) -> &'static str {
+        "speed_daemon"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(handler(stream, self.state.clone()))
+    }
+}
+
+async fn handler(
+    stream: crate::service::Stream,
+    state: std::sync::Arc<std::sync::Mutex<State>>,
+) -> anyhow::Result<()> {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut bf = tokio::io::BufReader::new(r);
+
+    // messages are sent by a dedicated task, so tickets and heartbeats can be
+    // written while waiting for the client
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<ServerMessage>();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            w.write_all(&msg.encode()).await?;
+        }
+
+        w.flush().await?;
+        w.shutdown().await?;
+        anyhow::Ok(())
+    });
+
+    let mut role = Role::Unknown;
+    let mut heartbeat: Option<HeartbeatGuard> = None;
+    let mut dispatcher: Option<DispatcherGuard> = None;
+
+    let result = loop {
+        let msg = match read_message(&mut bf).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+
+        match (msg, role) {
+            (ClientMessage::Plate { plate, timestamp }, Role::Camera { road, mile }) => {
+                tracing::info!(
+                    "plate {} at road {} mile {} at {}",
+                    plate,
+                    road,
+                    mile,
+                    timestamp
+                );
+
+                let mut state = state.lock().unwrap();
+                for ticket in state.observe(&plate, road, mile, timestamp) {
+                    tracing::info!("ticket {:?}", ticket);
+                    state.dispatch(ticket);
+                }
+            }
+
+            (ClientMessage::WantHeartbeat { interval }, _) => {
+                if heartbeat.is_some() {
+                    break Err(anyhow::anyhow!("heartbeat already requested"));
+                }
+
+                let tx = tx.clone();
+                heartbeat = Some(HeartbeatGuard(tokio::spawn(async move {
+                    if interval == 0 {
+                        return;
+                    }
+
+                    let period = std::time::Duration::from_millis(interval as u64 * 100);
+                    let mut ticker = tokio::time::interval(period);
+                    ticker.tick().await;
+
+                    loop {
+                        ticker.tick().await;
+                        if tx.send(ServerMessage::Heartbeat).is_err() {
+                            break;
+                        }
+                    }
+                })));
+            }
+
+            (ClientMessage::IAmCamera { road, mile, limit }, Role::Unknown) => {
+                tracing::info!("camera at road {} mile {} limit {}", road, mile, limit);
+
+                state.lock().unwrap().limits.insert(road, limit);
+                role = Role::Camera { road, mile };
+            }
+
+            (ClientMessage::IAmDispatcher { roads }, Role::Unknown) => {
+                tracing::info!("dispatcher for roads {:?}", roads);
+
+                let id = state.lock().unwrap().add_dispatcher(&roads, tx.clone());
+                dispatcher = Some(DispatcherGuard {
+                    id,
+                    state: state.clone(),
+                });
+                role = Role::Dispatcher;
+            }
+
+            (msg, _) => break Err(anyhow::anyhow!("unexpected message {:?}", msg)),
+        }
+    };
+
+    if let Err(e) = &result {
+        tracing::warn!("client error: {}", e);
+        _ = tx.send(ServerMessage::Error { msg: e.to_string() });
+    }
+
+    drop(heartbeat);
+    drop(dispatcher);
+    drop(tx);
+    writer.await??;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestServer;
+
+    #[tokio::test]
+    async fn example_ticket() {
+        let server = TestServer::start(SpeedDaemon::new()).await;
+
+        let mut camera1 = server.binary_client().await;
+        camera1
+            .send(&[0x80, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x3c])
+            .await;
+        camera1
+            .send(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00])
+            .await;
+
+        let mut camera2 = server.binary_client().await;
+        camera2
+            .send(&[0x80, 0x00, 0x7b, 0x00, 0x09, 0x00, 0x3c])
+            .await;
+        camera2
+            .send(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x2d])
+            .await;
+
+        let mut dispatcher = server.binary_client().await;
+        dispatcher.send(&[0x81, 0x01, 0x00, 0x7b]).await;
+
+        assert_eq!(
+            dispatcher.recv_exact(22).await,
+            [
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x09, 0x00, 0x00, 0x00, 0x2d, 0x1f, 0x40
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn heartbeat() {
+        let server = TestServer::start(SpeedDaemon::new()).await;
+        let mut client = server.binary_client().await;
+
+        // every 0.1 seconds
+        client.send(&[0x40, 0x00, 0x00, 0x00, 0x01]).await;
+
+        for _ in 0..3 {
+            assert_eq!(client.recv_exact(1).await, [0x41]);
+        }
+    }
+
+    #[tokio::test]
+    async fn plate_from_unidentified_client() {
+        let server = TestServer::start(SpeedDaemon::new()).await;
+        let mut client = server.binary_client().await;
+
+        client
+            .send(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00])
+            .await;
+
+        let buffer = client.recv_to_end().await;
+        assert_eq!(buffer[0], 0x10);
+        assert_eq!(buffer.len(), 2 + buffer[1] as usize);
+    }
+
+    #[tokio::test]
+    async fn illegal_message() {
+        let server = TestServer::start(SpeedDaemon::new()).await;
+        let mut client = server.binary_client().await;
+
+        client.send(&[0xff]).await;
+
+        assert_eq!(client.recv_to_end().await, b"\x10\x0billegal msg");
+    }
+
+    #[test]
+    fn one_ticket_per_day() {
+        let mut state = State::default();
+        state.limits.insert(1, 60);
+
+        assert!(state.observe("CAR", 1, 0, 0).is_empty());
+
+        // 100 mph
+        let tickets = state.observe("CAR", 1, 100, 3600);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!(tickets[0].speed, 10000);
+
+        // speeding again the same day
+        assert!(state.observe("CAR", 1, 200, 7200).is_empty());
+
+        // speeding the next day
+        assert!(state.observe("CAR", 1, 1000, SECONDS_PER_DAY).is_empty());
+        let tickets = state.observe("CAR", 1, 1100, SECONDS_PER_DAY + 3600);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!(tickets[0].mile1, 1000);
+        assert_eq!(tickets[0].timestamp2, SECONDS_PER_DAY + 3600);
+    }
+
+    #[test]
+    fn limit_tolerance() {
+        let mut state = State::default();
+        state.limits.insert(1, 60);
+
+        // 60.4 mph is not enough for a ticket
+        assert!(state.observe("SLOW", 1, 0, 0).is_empty());
+        assert!(state.observe("SLOW", 1, 604, 36000).is_empty());
+
+        // 60.5 mph is
+        assert!(state.observe("FAST", 1, 0, 0).is_empty());
+        assert_eq!(state.observe("FAST", 1, 605, 36000).len(), 1);
+    }
+
+    #[test]
+    fn queued_tickets() {
+        let mut state = State::default();
+        state.limits.insert(7, 10);
+
+        state.observe("CAR", 7, 0, 0);
+        for ticket in state.observe("CAR", 7, 100, 3600) {
+            state.dispatch(ticket);
+        }
+        assert_eq!(state.pending[&7].len(), 1);
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.add_dispatcher(&[7], tx);
+
+        assert!(!state.pending.contains_key(&7));
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::Ticket(_))));
+    }
+}
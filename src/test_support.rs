@@ -0,0 +1,228 @@
+//! Helpers shared by the tests of the services: a server running on an
+//! ephemeral port and clients for the kinds of protocol the exercises speak.
+//!
+//! Every read is bounded by [`TIMEOUT`], so that a test waiting for an answer
+//! that never comes fails instead of hanging.
+
+use crate::service::{Listener, Options, Service};
+use crate::shutdown::Shutdown;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/// Time a single step of a test can take before failing.
+pub const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Awaits the future, panicking if it does not complete within [`TIMEOUT`].
+pub async fn timeout<F: std::future::Future>(f: F) -> F::Output {
+    tokio::time::timeout(TIMEOUT, f)
+        .await
+        .expect("test step timed out")
+}
+
+/// A service listening on an ephemeral port of localhost, shut down when
+/// dropped.
+pub struct TestServer {
+    pub addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+    task: tokio::task::JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestServer {
+    pub async fn start<S: Service>(service: S) -> Self {
+        Self::start_with(std::sync::Arc::new(service), Options::default()).await
+    }
+
+    /// Starts a service the test keeps a reference to, with custom options.
+    pub async fn start_with<S: Service>(service: std::sync::Arc<S>, options: Options) -> Self {
+        let listener = Listener::bind(
+            service.transport(),
+            std::net::SocketAddr::from(([127, 0, 0, 1], 0)),
+        )
+        .await
+        .expect("open a listener");
+
+        let Ok(crate::service::Address::Inet(addr)) = listener.local_addr() else {
+            panic!("listener has an inet address");
+        };
+        let shutdown = Shutdown::new(std::time::Duration::from_secs(1));
+        let task = tokio::spawn(service.run(listener, options, shutdown.clone()));
+
+        Self {
+            addr,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Triggers the shutdown of the server, leaving it to drain connections.
+    pub fn trigger_shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Shuts the server down and waits for it to stop.
+    pub async fn stop(mut self) -> anyhow::Result<()> {
+        self.shutdown.trigger();
+        timeout(&mut self.task)
+            .await
+            .expect("server task completes")
+    }
+
+    pub async fn line_client(&self) -> LineClient {
+        LineClient::connect(self.addr).await
+    }
+
+    pub async fn binary_client(&self) -> BinaryClient {
+        BinaryClient::connect(self.addr).await
+    }
+
+    pub async fn udp_client(&self) -> UdpClient {
+        UdpClient::connect(self.addr).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+        self.task.abort();
+    }
+}
+
+/// Client of a protocol made of newline terminated lines.
+pub struct LineClient {
+    stream: tokio::io::BufReader<tokio::net::TcpStream>,
+}
+
+impl LineClient {
+    pub async fn connect(addr: std::net::SocketAddr) -> Self {
+        let stream = timeout(tokio::net::TcpStream::connect(addr))
+            .await
+            .expect("connection with local works");
+
+        Self {
+            stream: tokio::io::BufReader::new(stream),
+        }
+    }
+
+    /// Sends the line, adding the newline.
+    pub async fn send(&mut self, line: &str) {
+        let mut buffer = line.as_bytes().to_vec();
+        buffer.push(b'\n');
+        self.send_raw(&buffer).await;
+    }
+
+    /// Sends the bytes as they are.
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        timeout(self.stream.write_all(bytes))
+            .await
+            .expect("to write payload");
+    }
+
+    /// Reads the next line without the newline, `None` means that the server
+    /// closed the connection.
+    pub async fn recv(&mut self) -> Option<String> {
+        let mut buffer = vec![];
+        let n = timeout(self.stream.read_until(b'\n', &mut buffer))
+            .await
+            .expect("to read line");
+
+        if n == 0 {
+            return None;
+        }
+
+        if buffer.last() == Some(&b'\n') {
+            buffer.pop();
+        }
+
+        Some(String::from_utf8(buffer).expect("line is utf8"))
+    }
+
+    /// Closes the write half, the server sees the end of the stream.
+    pub async fn shutdown(&mut self) {
+        timeout(self.stream.shutdown()).await.expect("shutdown");
+    }
+}
+
+/// Client of a binary protocol, usable with the message readers and writers
+/// of the services.
+pub struct BinaryClient {
+    pub stream: tokio::io::BufReader<tokio::net::TcpStream>,
+}
+
+impl BinaryClient {
+    pub async fn connect(addr: std::net::SocketAddr) -> Self {
+        let stream = timeout(tokio::net::TcpStream::connect(addr))
+            .await
+            .expect("connection with local works");
+
+        Self {
+            stream: tokio::io::BufReader::new(stream),
+        }
+    }
+
+    pub async fn send(&mut self, bytes: &[u8]) {
+        timeout(self.stream.write_all(bytes))
+            .await
+            .expect("to write payload");
+    }
+
+    pub async fn recv_exact(&mut self, n: usize) -> Vec<u8> {
+        let mut buffer = vec![0; n];
+        timeout(self.stream.read_exact(&mut buffer))
+            .await
+            .expect("to read response");
+
+        buffer
+    }
+
+    /// Reads everything until the server closes the connection.
+    pub async fn recv_to_end(&mut self) -> Vec<u8> {
+        let mut buffer = vec![];
+        timeout(self.stream.read_to_end(&mut buffer))
+            .await
+            .expect("to read until the end");
+
+        buffer
+    }
+
+    /// Closes the write half, the server sees the end of the stream.
+    pub async fn shutdown(&mut self) {
+        timeout(self.stream.shutdown()).await.expect("shutdown");
+    }
+}
+
+/// Client of a datagram protocol.
+pub struct UdpClient {
+    socket: tokio::net::UdpSocket,
+}
+
+impl UdpClient {
+    pub async fn connect(addr: std::net::SocketAddr) -> Self {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("bind a client socket");
+        socket.connect(addr).await.expect("connect to the server");
+
+        Self { socket }
+    }
+
+    pub async fn send(&self, datagram: &[u8]) {
+        self.socket.send(datagram).await.expect("to send datagram");
+    }
+
+    pub async fn recv(&self) -> Vec<u8> {
+        self.try_recv(TIMEOUT)
+            .await
+            .expect("datagram received in time")
+    }
+
+    /// Waits for a datagram up to `wait`, `None` means that nothing arrived.
+    pub async fn try_recv(&self, wait: std::time::Duration) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; 2048];
+        let n = tokio::time::timeout(wait, self.socket.recv(&mut buffer))
+            .await
+            .ok()?
+            .expect("to receive datagram");
+
+        buffer.truncate(n);
+        Some(buffer)
+    }
+}
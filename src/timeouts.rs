@@ -0,0 +1,272 @@
+//! Timeouts of the connections, so that silent clients do not hold a task
+//! forever.
+
+/// Timeouts applied to every connection of a service, `None` disables them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Max time without traffic in either direction.
+    pub idle: Option<std::time::Duration>,
+
+    /// Max time a read waits for the client to send something.
+    pub read: Option<std::time::Duration>,
+
+    /// Max duration of the whole connection.
+    pub session: Option<std::time::Duration>,
+}
+
+/// Timeout that closed a connection, carried by the `TimedOut` errors of
+/// [`Timed`] so that services can tell the client why it is disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expired {
+    Idle,
+    Read,
+    Session,
+}
+
+impl std::fmt::Display for Expired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Expired::Idle => "idle for too long",
+            Expired::Read => "nothing received for too long",
+            Expired::Session => "connected for too long",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for Expired {}
+
+impl Expired {
+    /// Returns the timeout behind the error, if that is what it is.
+    pub fn from_io(e: &std::io::Error) -> Option<Expired> {
+        e.get_ref()?.downcast_ref::<Expired>().copied()
+    }
+}
+
+/// Stream failing the reads and writes that are waiting past a timeout.
+pub struct Timed<S> {
+    inner: S,
+    timeouts: Timeouts,
+    started: tokio::time::Instant,
+    last_activity: tokio::time::Instant,
+    /// When the pending read started waiting, `None` if no read is waiting.
+    read_started: Option<tokio::time::Instant>,
+    // reads and writes may be polled by different tasks, each needs its own
+    // timer to be woken up
+    read_timer: std::pin::Pin<Box<tokio::time::Sleep>>,
+    write_timer: std::pin::Pin<Box<tokio::time::Sleep>>,
+}
+
+impl<S> Timed<S> {
+    pub fn new(inner: S, timeouts: Timeouts) -> Self {
+        let now = tokio::time::Instant::now();
+
+        Self {
+            inner,
+            timeouts,
+            started: now,
+            last_activity: now,
+            read_started: None,
+            read_timer: Box::pin(tokio::time::sleep_until(now)),
+            write_timer: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// The earliest deadline among the ones that apply, with its timeout.
+    fn deadline(&self, reading: bool) -> Option<(tokio::time::Instant, Expired)> {
+        let read = match (reading, self.read_started) {
+            (true, Some(started)) => self.timeouts.read.map(|t| (started + t, Expired::Read)),
+            _ => None,
+        };
+
+        [
+            self.timeouts
+                .session
+                .map(|t| (self.started + t, Expired::Session)),
+            self.timeouts
+                .idle
+                .map(|t| (self.last_activity + t, Expired::Idle)),
+            read,
+        ]
+        .into_iter()
+        .flatten()
+        .min_by_key(|(deadline, _)| *deadline)
+    }
+}
+
+/// Waits for the deadline on the timer, the error to return once it passed.
+fn poll_deadline(
+    timer: &mut std::pin::Pin<Box<tokio::time::Sleep>>,
+    deadline: Option<(tokio::time::Instant, Expired)>,
+    cx: &mut std::task::Context<'_>,
+) -> std::task::Poll<std::io::Error> {
+    use std::future::Future;
+
+    let Some((deadline, expired)) = deadline else {
+        return std::task::Poll::Pending;
+    };
+
+    if timer.deadline() != deadline {
+        timer.as_mut().reset(deadline);
+    }
+
+    timer
+        .as_mut()
+        .poll(cx)
+        .map(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, expired))
+}
+
+impl<S: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for Timed<S> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        match std::pin::Pin::new(&mut this.inner).poll_read(cx, buf) {
+            std::task::Poll::Ready(res) => {
+                this.last_activity = tokio::time::Instant::now();
+                this.read_started = None;
+                std::task::Poll::Ready(res)
+            }
+            std::task::Poll::Pending => {
+                this.read_started
+                    .get_or_insert_with(tokio::time::Instant::now);
+                let deadline = this.deadline(true);
+                poll_deadline(&mut this.read_timer, deadline, cx).map(Err)
+            }
+        }
+    }
+}
+
+impl<S: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for Timed<S> {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        match std::pin::Pin::new(&mut this.inner).poll_write(cx, buf) {
+            std::task::Poll::Ready(res) => {
+                this.last_activity = tokio::time::Instant::now();
+                std::task::Poll::Ready(res)
+            }
+            std::task::Poll::Pending => {
+                let deadline = this.deadline(false);
+                poll_deadline(&mut this.write_timer, deadline, cx).map(Err)
+            }
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn secs(n: u64) -> Option<std::time::Duration> {
+        Some(std::time::Duration::from_secs(n))
+    }
+
+    async fn expired<F: std::future::Future<Output = std::io::Result<usize>>>(f: F) -> Expired {
+        let e = f.await.expect_err("timed out");
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        Expired::from_io(&e).expect("timeout error")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_timeout() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let timeouts = Timeouts {
+            read: secs(5),
+            ..Timeouts::default()
+        };
+        let mut stream = Timed::new(server, timeouts);
+        let mut buffer = [0; 8];
+
+        // data arriving before the timeout is read
+        let write = async {
+            tokio::time::sleep(std::time::Duration::from_secs(4)).await;
+            client.write_all(b"hi").await.unwrap();
+        };
+        let (n, _) = tokio::join!(stream.read(&mut buffer), write);
+        assert_eq!(n.unwrap(), 2);
+
+        // the timer starts again with the next read
+        let started = tokio::time::Instant::now();
+        assert_eq!(expired(stream.read(&mut buffer)).await, Expired::Read);
+        assert_eq!(started.elapsed(), std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout() {
+        let (_client, server) = tokio::io::duplex(64);
+        let timeouts = Timeouts {
+            idle: secs(10),
+            ..Timeouts::default()
+        };
+        let mut stream = Timed::new(server, timeouts);
+
+        // writing is some activity, the idle time starts again
+        tokio::time::sleep(std::time::Duration::from_secs(6)).await;
+        stream.write_all(b"ping").await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        let mut buffer = [0; 8];
+        assert_eq!(expired(stream.read(&mut buffer)).await, Expired::Idle);
+        assert_eq!(started.elapsed(), std::time::Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_timeout() {
+        let (_client, server) = tokio::io::duplex(4);
+        let timeouts = Timeouts {
+            idle: secs(10),
+            session: secs(3),
+            ..Timeouts::default()
+        };
+        let mut stream = Timed::new(server, timeouts);
+
+        // the client never reads, writes get stuck once the buffer is full
+        stream.write_all(b"0123").await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(expired(stream.write(b"4567")).await, Expired::Session);
+        assert_eq!(started.elapsed(), std::time::Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_timeouts() {
+        let (_client, server) = tokio::io::duplex(64);
+        let mut stream = Timed::new(server, Timeouts::default());
+
+        let mut buffer = [0; 8];
+        let read = tokio::time::timeout(
+            std::time::Duration::from_secs(3600),
+            stream.read(&mut buffer),
+        )
+        .await;
+        assert!(read.is_err());
+    }
+}
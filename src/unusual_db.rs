@@ -1,14 +1,91 @@
-pub async fn run() -> anyhow::Result<()> {
-    let listener = tokio::net::UdpSocket::bind("0.0.0.0:8000").await?;
-    tracing::info!("Listening on {}", listener.local_addr()?);
+/// Settings of the store.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Value of the read-only `version` key.
+    pub version: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: "1.0.0".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // the response has to fit in a datagram of the protocol
+        if "version=".len() + self.version.len() >= 1000 {
+            anyhow::bail!("version is too long");
+        }
+
+        Ok(())
+    }
+}
+
+/// Problem 4: a key-value store over UDP.
+#[derive(Default)]
+pub struct UnusualDb {
+    config: Config,
+}
+
+impl UnusualDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self { config }
+    }
+}
 
+impl crate::service::Service for UnusualDb {
+    fn name(&self) -> &'static str {
+        "unusual_db"
+    }
+
+    fn transport(&self) -> crate::service::Transport {
+        crate::service::Transport::Udp
+    }
+
+    fn run(
+        self: std::sync::Arc<Self>,
+        listener: crate::service::Listener,
+        _options: crate::service::Options,
+        shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move {
+            match listener {
+                crate::service::Listener::Udp(socket) => run(socket, &self.config, shutdown).await,
+                _ => anyhow::bail!("unusual_db needs a udp socket"),
+            }
+        })
+    }
+}
+
+async fn run(
+    listener: tokio::net::UdpSocket,
+    config: &Config,
+    shutdown: crate::shutdown::Shutdown,
+) -> anyhow::Result<()> {
     let mut db = std::collections::HashMap::new();
 
+    let r = crate::metrics::registry();
+    let traffic = crate::metrics::ConnectionMetrics::new("unusual_db");
+    let datagrams = r.counter(
+        "unusual_db_datagrams_total",
+        "Datagrams received.",
+        "unusual_db",
+    );
+    let keys = r.gauge("unusual_db_keys", "Keys stored.", "unusual_db");
+
     let mut buffer = vec![0; 1024];
     loop {
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => {
-                tracing::info!("Received Ctrl-C");
+            _ = shutdown.wait() => {
+                tracing::info!("Shutting down");
                 break;
             }
 
@@ -16,6 +93,8 @@ pub async fn run() -> anyhow::Result<()> {
                 match v {
                     Ok((size, addr)) => {
                         tracing::info!("Received {} bytes from {}", size, addr);
+                        datagrams.inc();
+                        traffic.received.add(size as u64);
 
                         let buffer = &buffer[..size];
                         tracing::info!(
@@ -25,8 +104,12 @@ pub async fn run() -> anyhow::Result<()> {
                             std::str::from_utf8(buffer)
                         );
 
-                        if let Some(response) = do_it(buffer, &mut db)? {
-                            listener.send_to(response.as_bytes(), addr).await?;
+                        let response = do_it(buffer, &mut db, &config.version)?;
+                        keys.set(db.len() as i64);
+
+                        if let Some(response) = response {
+                            let sent = listener.send_to(response.as_bytes(), addr).await?;
+                            traffic.sent.add(sent as u64);
                         }
                     }
 
@@ -41,9 +124,13 @@ pub async fn run() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn do_it(
+/// Applies a request to the database, returning the response to send back
+/// if any: `key=value` inserts, anything else retrieves. The `version` key is
+/// read-only.
+pub fn do_it(
     buffer: &[u8],
     db: &mut std::collections::HashMap<String, String>,
+    version: &str,
 ) -> anyhow::Result<Option<String>> {
     let equal_sign = b'=';
 
@@ -61,7 +148,7 @@ fn do_it(
         let key = String::from_utf8_lossy(buffer).to_string();
 
         let v = if &key == "version" {
-            "version=1.0.0".to_string()
+            format!("version={version}")
         } else {
             let value = db.get(&key).map(|x| x.as_str()).unwrap_or("");
             let mut result = key.clone();
@@ -79,6 +166,7 @@ fn do_it(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::TestServer;
 
     #[test]
     fn retrieve() {
@@ -87,15 +175,15 @@ mod tests {
 
             // insert a value
             let buffer = b"foo=bar";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
 
             let buffer = b"foo";
-            let result = do_it(buffer, &mut db);
+            let result = do_it(buffer, &mut db, "1.0.0");
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), Some("foo=bar".to_string()));
 
             let buffer = b"version";
-            let result = do_it(buffer, &mut db);
+            let result = do_it(buffer, &mut db, "1.0.0");
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), Some("version=1.0.0".to_string()));
         }
@@ -104,7 +192,7 @@ mod tests {
 
             // search for a non-existing value
             let buffer = b"aaa";
-            let result = do_it(buffer, &mut db);
+            let result = do_it(buffer, &mut db, "1.0.0");
             assert!(result.is_ok());
             assert_eq!(result.unwrap(), Some("aaa=".to_string()));
         }
@@ -115,37 +203,37 @@ mod tests {
         {
             let mut db = std::collections::HashMap::new();
             let buffer = b"foo=bar";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
             assert_eq!(db.get("foo"), Some(&"bar".to_string()));
         }
         {
             let mut db = std::collections::HashMap::new();
             let buffer = b"foo=bar=f";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
             assert_eq!(db.get("foo"), Some(&"bar=f".to_string()));
         }
         {
             let mut db = std::collections::HashMap::new();
             let buffer = b"foo===";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
             assert_eq!(db.get("foo"), Some(&"==".to_string()));
         }
         {
             let mut db = std::collections::HashMap::new();
             let buffer = b"foo=";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
             assert_eq!(db.get("foo"), Some(&"".to_string()));
         }
         {
             let mut db = std::collections::HashMap::new();
             let buffer = b"=bar";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
             assert_eq!(db.get(""), Some(&"bar".to_string()));
         }
         {
             let mut db = std::collections::HashMap::new();
             let buffer = b"version=111";
-            assert!(do_it(buffer, &mut db).is_ok());
+            assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
             assert_eq!(db.get("version"), None); // never store version
         }
     }
@@ -154,11 +242,36 @@ mod tests {
     fn update() {
         let mut db = std::collections::HashMap::new();
         let buffer = b"foo=bar";
-        assert!(do_it(buffer, &mut db).is_ok());
+        assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
         assert_eq!(db.get("foo"), Some(&"bar".to_string()));
 
         let buffer = b"foo=bar2";
-        assert!(do_it(buffer, &mut db).is_ok());
+        assert!(do_it(buffer, &mut db, "1.0.0").is_ok());
         assert_eq!(db.get("foo"), Some(&"bar2".to_string()));
     }
+
+    #[tokio::test]
+    async fn over_udp() {
+        let server = TestServer::start(UnusualDb::new()).await;
+        let client = server.udp_client().await;
+
+        client.send(b"foo=bar").await;
+        client.send(b"foo").await;
+        assert_eq!(client.recv().await, b"foo=bar");
+
+        client.send(b"version").await;
+        assert_eq!(client.recv().await, b"version=1.0.0");
+    }
+
+    #[tokio::test]
+    async fn configured_version() {
+        let server = TestServer::start(UnusualDb::with_config(Config {
+            version: "Ken's Key-Value Store 1.0".to_string(),
+        }))
+        .await;
+        let client = server.udp_client().await;
+
+        client.send(b"version").await;
+        assert_eq!(client.recv().await, b"version=Ken's Key-Value Store 1.0");
+    }
 }
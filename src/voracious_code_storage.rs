@@ -0,0 +1,425 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/// Files and their revisions, the first revision is `r1`.
+#[derive(Default)]
+pub struct Storage {
+    files: std::collections::BTreeMap<String, Vec<Vec<u8>>>,
+}
+
+impl Storage {
+    /// Stores a new revision of the file, unless the content is the same of
+    /// the latest one, and returns the revision number.
+    pub fn put(&mut self, name: &str, data: Vec<u8>) -> usize {
+        let revisions = self.files.entry(name.to_string()).or_default();
+
+        if revisions.last() != Some(&data) {
+            revisions.push(data);
+        }
+
+        revisions.len()
+    }
+
+    /// Returns the revision of the file, the latest one if not given.
+    pub fn get(&self, name: &str, revision: Option<usize>) -> Result<&[u8], &'static str> {
+        let revisions = self.files.get(name).ok_or("no such file")?;
+
+        let revision = revision.unwrap_or(revisions.len());
+        revision
+            .checked_sub(1)
+            .and_then(|i| revisions.get(i))
+            .map(|data| data.as_slice())
+            .ok_or("no such revision")
+    }
+
+    /// Lists the files and the directories directly inside the directory.
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let dir = if dir.ends_with('/') {
+            dir.to_string()
+        } else {
+            format!("{dir}/")
+        };
+
+        let mut entries = std::collections::BTreeMap::new();
+        for (name, revisions) in self.files.range(dir.clone()..) {
+            let Some(rest) = name.strip_prefix(&dir) else {
+                break;
+            };
+
+            match rest.split_once('/') {
+                Some((sub, _)) => entries.insert(format!("{sub}/"), "DIR".to_string()),
+                None => entries.insert(rest.to_string(), format!("r{}", revisions.len())),
+            };
+        }
+
+        entries
+            .into_iter()
+            .map(|(name, info)| format!("{name} {info}"))
+            .collect()
+    }
+}
+
+fn name_is_valid(name: &str) -> bool {
+    name.starts_with('/')
+        && !name.contains("//")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '-' | '_'))
+}
+
+fn file_name_is_valid(name: &str) -> bool {
+    name_is_valid(name) && !name.ends_with('/')
+}
+
+fn is_text(data: &[u8]) -> bool {
+    data.iter()
+        .all(|&c| c.is_ascii_graphic() || matches!(c, b' ' | b'\n' | b'\r' | b'\t'))
+}
+
+fn parse_revision(revision: &str) -> Option<usize> {
+    revision.strip_prefix('r').unwrap_or(revision).parse().ok()
+}
+
+/// Command lines longer than this close the connection.
+const MAX_LINE: usize = 4096;
+
+/// Settings of the storage.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Largest file that can be stored, bigger ones close the connection.
+    pub max_file_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_file_size: 1 << 20,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Problem 10: a versioned file storage.
+pub struct VoraciousCodeStorage {
+    config: Config,
+    storage: std::sync::Arc<std::sync::Mutex<Storage>>,
+}
+
+impl VoraciousCodeStorage {
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            config,
+            storage: std::sync::Arc::new(std::sync::Mutex::new(Storage::default())),
+        }
+    }
+}
+
+impl Default for VoraciousCodeStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl crate::service::Service for VoraciousCodeStorage {
+    fn name(&self) -> &'static str {
+        "voracious_code_storage"
+    }
+
+    fn handle(
+        self: std::sync::Arc<Self>,
+        stream: crate::service::Stream,
+        _shutdown: crate::shutdown::Shutdown,
+    ) -> crate::service::BoxFuture<anyhow::Result<()>> {
+        Box::pin(async move { handler(stream, &self.storage, &self.config).await })
+    }
+}
+
+async fn handler(
+    stream: crate::service::Stream,
+    storage: &std::sync::Mutex<Storage>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut bf = tokio::io::BufReader::new(r);
+    let mut buffer = vec![];
+
+    loop {
+        w.write_all(b"READY\n").await?;
+
+        buffer.clear();
+        let n = (&mut bf)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut buffer)
+            .await?;
+        if n == 0 {
+            break;
+        }
+
+        if n == MAX_LINE && buffer.last() != Some(&b'\n') {
+            w.write_all(b"ERR line too long\n").await?;
+            break;
+        }
+
+        let line = String::from_utf8_lossy(&buffer).to_string();
+        tracing::info!("{:?}", line.trim_end());
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let method = args.first().copied().unwrap_or_default();
+
+        let response = match method.to_ascii_uppercase().as_str() {
+            "HELP" => Ok(b"OK usage: HELP|GET|PUT|LIST\n".to_vec()),
+            "PUT" => match args[..] {
+                [_, name, length] => {
+                    let Ok(length) = length.parse::<usize>() else {
+                        w.write_all(b"ERR illegal file length\n").await?;
+                        continue;
+                    };
+
+                    // a too large file cannot be skipped, its data would be
+                    // mistaken for commands
+                    if length > config.max_file_size {
+                        w.write_all(b"ERR file too large\n").await?;
+                        break;
+                    }
+
+                    // the data is read even when the request is not valid,
+                    // so it is not mistaken for commands
+                    let mut data = Vec::with_capacity(length);
+                    (&mut bf).take(length as u64).read_to_end(&mut data).await?;
+                    if data.len() < length {
+                        break;
+                    }
+
+                    put(storage, name, data)
+                }
+                _ => Err("usage: PUT file length newline data".to_string()),
+            },
+            "GET" => get(storage, &args[1..]),
+            "LIST" => list(storage, &args[1..]),
+            _ => {
+                w.write_all(format!("ERR illegal method: {method}\n").as_bytes())
+                    .await?;
+                break;
+            }
+        };
+
+        match response {
+            Ok(response) => w.write_all(&response).await?,
+            Err(e) => w.write_all(format!("ERR {e}\n").as_bytes()).await?,
+        }
+    }
+
+    w.flush().await?;
+    w.shutdown().await?;
+
+    Ok(())
+}
+
+fn put(storage: &std::sync::Mutex<Storage>, name: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !file_name_is_valid(name) {
+        return Err("illegal file name".to_string());
+    }
+
+    if !is_text(&data) {
+        return Err("text files only".to_string());
+    }
+
+    let revision = storage.lock().unwrap().put(name, data);
+    Ok(format!("OK r{revision}\n").into_bytes())
+}
+
+fn get(storage: &std::sync::Mutex<Storage>, args: &[&str]) -> Result<Vec<u8>, String> {
+    let (name, revision) = match args {
+        [name] => (name, None),
+        [name, revision] => (name, Some(revision)),
+        _ => return Err("usage: GET file [revision]".to_string()),
+    };
+
+    if !file_name_is_valid(name) {
+        return Err("illegal file name".to_string());
+    }
+
+    let revision = match revision {
+        Some(r) => Some(parse_revision(r).ok_or("no such revision")?),
+        None => None,
+    };
+
+    let storage = storage.lock().unwrap();
+    let data = storage.get(name, revision)?;
+
+    let mut response = format!("OK {}\n", data.len()).into_bytes();
+    response.extend_from_slice(data);
+    Ok(response)
+}
+
+fn list(storage: &std::sync::Mutex<Storage>, args: &[&str]) -> Result<Vec<u8>, String> {
+    let [dir] = args else {
+        return Err("usage: LIST dir".to_string());
+    };
+
+    if !name_is_valid(dir) {
+        return Err("illegal dir name".to_string());
+    }
+
+    let entries = storage.lock().unwrap().list(dir);
+
+    let mut response = format!("OK {}\n", entries.len());
+    for entry in entries {
+        response.push_str(&entry);
+        response.push('\n');
+    }
+
+    Ok(response.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestServer;
+
+    #[tokio::test]
+    async fn revisions() {
+        let server = TestServer::start(VoraciousCodeStorage::new()).await;
+        let mut client = server.line_client().await;
+
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send_raw(b"PUT /test.txt 6\nhello\n").await;
+        assert_eq!(client.recv().await.unwrap(), "OK r1");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        // same content, same revision
+        client.send_raw(b"PUT /test.txt 6\nhello\n").await;
+        assert_eq!(client.recv().await.unwrap(), "OK r1");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send_raw(b"put /test.txt 6\nworld\n").await;
+        assert_eq!(client.recv().await.unwrap(), "OK r2");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("GET /test.txt").await;
+        assert_eq!(client.recv().await.unwrap(), "OK 6");
+        assert_eq!(client.recv().await.unwrap(), "world");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("GET /test.txt r1").await;
+        assert_eq!(client.recv().await.unwrap(), "OK 6");
+        assert_eq!(client.recv().await.unwrap(), "hello");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("GET /test.txt 3").await;
+        assert_eq!(client.recv().await.unwrap(), "ERR no such revision");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("GET /other.txt").await;
+        assert_eq!(client.recv().await.unwrap(), "ERR no such file");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+    }
+
+    #[tokio::test]
+    async fn list_directories() {
+        let server = TestServer::start(VoraciousCodeStorage::new()).await;
+        let mut client = server.line_client().await;
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        for put in [
+            "PUT /a.txt 1\na",
+            "PUT /dir/b.txt 1\nb",
+            "PUT /dir/sub/c.txt 1\nc",
+            "PUT /dir/b.txt 2\nbb",
+        ] {
+            client.send_raw(put.as_bytes()).await;
+            assert!(client.recv().await.unwrap().starts_with("OK r"));
+            assert_eq!(client.recv().await.unwrap(), "READY");
+        }
+
+        client.send("LIST /").await;
+        assert_eq!(client.recv().await.unwrap(), "OK 2");
+        assert_eq!(client.recv().await.unwrap(), "a.txt r1");
+        assert_eq!(client.recv().await.unwrap(), "dir/ DIR");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("LIST /dir").await;
+        assert_eq!(client.recv().await.unwrap(), "OK 2");
+        assert_eq!(client.recv().await.unwrap(), "b.txt r2");
+        assert_eq!(client.recv().await.unwrap(), "sub/ DIR");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("LIST /nothing/").await;
+        assert_eq!(client.recv().await.unwrap(), "OK 0");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let server = TestServer::start(VoraciousCodeStorage::new()).await;
+        let mut client = server.line_client().await;
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        for (request, expected) in [
+            ("HELP\n", "OK usage: HELP|GET|PUT|LIST"),
+            ("PUT /a\n", "ERR usage: PUT file length newline data"),
+            ("PUT a.txt 1\nx", "ERR illegal file name"),
+            ("PUT /a//b 1\nx", "ERR illegal file name"),
+            ("PUT /a.txt 1\n\x01", "ERR text files only"),
+            ("GET\n", "ERR usage: GET file [revision]"),
+            ("GET /dir/\n", "ERR illegal file name"),
+            ("LIST\n", "ERR usage: LIST dir"),
+            ("LIST dir\n", "ERR illegal dir name"),
+        ] {
+            client.send_raw(request.as_bytes()).await;
+            assert_eq!(client.recv().await.unwrap(), expected, "{:?}", request);
+            assert_eq!(client.recv().await.unwrap(), "READY");
+        }
+
+        client.send("DELETE /a.txt").await;
+        assert_eq!(client.recv().await.unwrap(), "ERR illegal method: DELETE");
+        assert_eq!(client.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let server = TestServer::start(VoraciousCodeStorage::with_config(Config {
+            max_file_size: 10,
+        }))
+        .await;
+
+        let mut client = server.line_client().await;
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("PUT /a.txt ten").await;
+        assert_eq!(client.recv().await.unwrap(), "ERR illegal file length");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("PUT /a.txt 10").await;
+        client.send_raw(b"0123456789").await;
+        assert_eq!(client.recv().await.unwrap(), "OK r1");
+        assert_eq!(client.recv().await.unwrap(), "READY");
+
+        client.send("PUT /a.txt 100000000000").await;
+        assert_eq!(client.recv().await.unwrap(), "ERR file too large");
+        assert_eq!(client.recv().await, None);
+
+        let mut client = server.line_client().await;
+        assert_eq!(client.recv().await.unwrap(), "READY");
+        client.send_raw(&[b'a'; MAX_LINE]).await;
+        assert_eq!(client.recv().await.unwrap(), "ERR line too long");
+        assert_eq!(client.recv().await, None);
+    }
+
+    #[test]
+    fn text_content() {
+        assert!(is_text(b"fn main() {\n\tprintln!(\"hi\");\r\n}\n"));
+        assert!(!is_text(b"\x00"));
+        assert!(!is_text("è".as_bytes()));
+    }
+}